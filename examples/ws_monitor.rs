@@ -1,6 +1,6 @@
 use futures_util::StreamExt;
-use tokio_tungstenite::connect_async;
-use tokio::time::Duration; // Removed `timeout` import as it's no longer needed.
+use tokio::time::Duration;
+use tokio_tungstenite::connect_async; // Removed `timeout` import as it's no longer needed.
 
 #[tokio::main]
 async fn main() {
@@ -38,7 +38,10 @@ async fn main() {
         match msg {
             Ok(msg) => {
                 if msg.is_text() {
-                    println!("\nReceived WS message: {}", msg.to_text().unwrap_or("[unreadable text]"));
+                    println!(
+                        "\nReceived WS message: {}",
+                        msg.to_text().unwrap_or("[unreadable text]")
+                    );
                 } else if msg.is_ping() {
                     println!("\nReceived WS Ping");
                 } else if msg.is_pong() {
@@ -55,4 +58,4 @@ async fn main() {
         }
     }
     println!("WebSocket monitor stopped.");
-}
\ No newline at end of file
+}
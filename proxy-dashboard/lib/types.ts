@@ -12,18 +12,90 @@ export interface BackendRequestLog {
   status: number;
   response_time: Duration;
   client_ip: string;
+  upstream_path?: string;
+  upstream?: string;
+  attempts?: AttemptLog[];
+  grpc_status?: number;
+  unmatched?: boolean;
+}
+
+export interface UnmatchedPath {
+  path: string;
+  count: number;
+  last_client_ip: string;
+  last_seen: string;
+}
+
+export interface AttemptLog {
+  upstream: string;
+  status?: number;
+  error?: string;
+  response_time: Duration;
 }
 
 export interface SummaryMetrics {
   total_requests: number;
   total_errors: number;
+  unmatched_requests: number;
+  top_unmatched: UnmatchedPath[];
+  total_timeouts: number;
   active_connections: number;
   recent_logs: BackendRequestLog[];
   route_stats: Record<string, number>;
+  total_tunnels: number;
+  active_tunnels: number;
+  recent_tunnels: TunnelLog[];
+  circuit_breakers: Record<string, CircuitState>;
+}
+
+export interface TunnelLog {
+  timestamp: string;
+  route: string;
+  upstream: string;
+  path: string;
+  client_ip: string;
+  duration: Duration;
+  bytes_sent: number;
+  bytes_received: number;
+  closed_by: "client" | "upstream" | "idle_timeout" | "max_lifetime";
+}
+
+export type CircuitState = "closed" | "open" | "half_open";
+
+export interface CircuitBreakerEvent {
+  timestamp: string;
+  upstream: string;
+  state: CircuitState;
+}
+
+export interface ReloadEvent {
+  timestamp: string;
+  trigger: "file_change" | "sighup" | "admin_api";
+  success: boolean;
+  routes: number | null;
+  error: string | null;
+}
+
+export interface UpstreamHealthEvent {
+  timestamp: string;
+  upstream: string;
+  healthy: boolean;
+}
+
+export interface UpstreamStatus {
+  url: string;
+  routes: string[];
+  health_checked: boolean;
+  healthy: boolean;
+  last_checked: string | null;
+  ejected_until: string | null;
+  circuit: CircuitState;
+  outstanding: number;
 }
 
 export interface WsMessage {
-  type: "NewLog" | "MetricsUpdate";
+  type: "NewLog" | "MetricsUpdate" | "ConfigReload" | "UpstreamHealth" | "CircuitBreaker";
   log?: BackendRequestLog;
   metrics?: SummaryMetrics;
+  event?: ReloadEvent | UpstreamHealthEvent | CircuitBreakerEvent;
 }
\ No newline at end of file
@@ -0,0 +1,594 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use http::{HeaderMap, header::COOKIE};
+use rand::Rng;
+
+use crate::{
+    breaker::CircuitBreaker,
+    config::{HashKey, LoadBalancing, OutlierDetectionConfig, Route, Upstream},
+};
+
+/// Points on the consistent hash ring for each unit of an upstream's weight
+const RING_POINTS_PER_WEIGHT: u32 = 64;
+
+/// Most points one upstream gets on the ring, so weights above 100 get no
+/// bigger share of the keys than a weight of 100
+const MAX_RING_POINTS: u32 = 100 * RING_POINTS_PER_WEIGHT;
+
+/// Spreads one route's requests over its upstreams
+pub struct Balancer {
+    strategy: LoadBalancing,
+    targets: Vec<Upstream>,
+    /// Each target's current credit for smooth weighted round-robin
+    credits: Mutex<Vec<i64>>,
+    /// Whether the route has a circuit breaker. Breaker state is kept per
+    /// upstream URL, so routes without one must not be held back by the
+    /// breakers of other routes to the same upstream.
+    circuit_breaker: bool,
+    /// Consistent hash ring of (point, target), sorted by point
+    ring: Vec<(u64, usize)>,
+    next: AtomicUsize,
+}
+
+/// Live state of each upstream URL, shared by every route that uses it and
+/// kept across config reloads
+#[derive(Default)]
+pub struct UpstreamRegistry {
+    upstreams: Mutex<HashMap<String, Arc<UpstreamState>>>,
+}
+
+#[derive(Default)]
+pub struct UpstreamState {
+    outstanding: AtomicU64,
+    health: Mutex<Health>,
+    outlier: Mutex<Outlier>,
+    breaker: CircuitBreaker,
+}
+
+/// Outcome of the active health checks on an upstream
+#[derive(Default)]
+struct Health {
+    unhealthy: bool,
+    /// Probes in a row with the same outcome
+    streak: u32,
+    last_checked: Option<DateTime<Utc>>,
+}
+
+/// Outcome of passive outlier detection on an upstream
+#[derive(Default)]
+struct Outlier {
+    /// Failed requests in a row
+    failures: u32,
+    /// End of the latest ejection, which may have passed
+    ejected_until: Option<DateTime<Utc>>,
+    /// Ejections since the upstream last went `max_ejection_ms` without one,
+    /// which multiply the next ejection's time
+    ejections: u32,
+}
+
+/// Counts a request as outstanding on an upstream until dropped
+pub struct InFlight(Arc<UpstreamState>);
+
+impl Balancer {
+    pub fn new(route: &Route) -> Self {
+        let targets = route.targets();
+        let strategy = route
+            .load_balancing
+            .clone()
+            .unwrap_or(LoadBalancing::RoundRobin);
+        let ring = match strategy {
+            LoadBalancing::ConsistentHash { .. } => hash_ring(&targets),
+            _ => Vec::new(),
+        };
+        Self {
+            strategy,
+            credits: Mutex::new(vec![0; targets.len()]),
+            circuit_breaker: route.circuit_breaker.is_some(),
+            targets,
+            ring,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn targets(&self) -> &[Upstream] {
+        &self.targets
+    }
+
+    /// Picks the upstream for a request, leaving out upstreams that are
+    /// unavailable. `None` if none are available.
+    pub fn pick(
+        &self,
+        registry: &UpstreamRegistry,
+        headers: &HeaderMap,
+        client_ip: IpAddr,
+    ) -> Option<&Upstream> {
+        let states: Vec<Arc<UpstreamState>> = self
+            .targets
+            .iter()
+            .map(|target| registry.get(&target.url))
+            .collect();
+        let available: Vec<usize> = (0..self.targets.len())
+            .filter(|&i| {
+                states[i].is_available() && (!self.circuit_breaker || states[i].breaker.allows())
+            })
+            .collect();
+        if available.is_empty() {
+            return None;
+        }
+        let outstanding = |i: usize| states[i].outstanding();
+
+        let index = match &self.strategy {
+            LoadBalancing::RoundRobin => self.round_robin(&available),
+            LoadBalancing::WeightedRoundRobin => self.weighted_round_robin(&available),
+            LoadBalancing::LeastOutstanding => {
+                // Start from a rotating offset so ties are shared out
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..available.len())
+                    .map(|i| available[(start + i) % available.len()])
+                    .min_by_key(|&i| outstanding(i))?
+            }
+            LoadBalancing::RandomTwoChoices => {
+                let len = available.len();
+                if len < 2 {
+                    available[0]
+                } else {
+                    let mut rng = rand::rng();
+                    let first = rng.random_range(0..len);
+                    let second = (first + rng.random_range(1..len)) % len;
+                    let (first, second) = (available[first], available[second]);
+                    if outstanding(second) < outstanding(first) {
+                        second
+                    } else {
+                        first
+                    }
+                }
+            }
+            LoadBalancing::ConsistentHash { key } => match hash_key(key, headers, client_ip) {
+                Some(key) => {
+                    // Walk round the ring from the key's point to the first
+                    // available upstream, so only the keys of unavailable
+                    // upstreams move
+                    let point = hash(key.as_bytes());
+                    let slot = self.ring.partition_point(|&(p, _)| p < point);
+                    (0..self.ring.len())
+                        .map(|i| self.ring[(slot + i) % self.ring.len()].1)
+                        .find(|i| available.contains(i))?
+                }
+                // Requests without the key can go anywhere
+                None => self.round_robin(&available),
+            },
+        };
+        self.targets.get(index)
+    }
+
+    /// Records whether a request to `url` failed, ejecting the upstream
+    /// once it has failed `consecutive_failures` times in a row unless that
+    /// would eject more than `max_ejection_percent` of the upstreams.
+    /// Returns how long it was ejected for.
+    pub fn record_outcome(
+        &self,
+        registry: &UpstreamRegistry,
+        url: &str,
+        failed: bool,
+        config: &OutlierDetectionConfig,
+    ) -> Option<Duration> {
+        // Count the other ejected upstreams before locking this one, so two
+        // upstreams never wait on each other's lock
+        let ejected = self
+            .targets
+            .iter()
+            .filter(|target| target.url != url)
+            .filter(|target| registry.get(&target.url).ejected_until().is_some())
+            .count();
+
+        let upstream = registry.get(url);
+        let mut outlier = upstream.outlier.lock().unwrap();
+        if !failed {
+            outlier.failures = 0;
+            return None;
+        }
+        outlier.failures += 1;
+
+        let now = Utc::now();
+        let max_ejection = Duration::from_millis(config.max_ejection_ms);
+        let ejected_now = outlier.ejected_until.is_some_and(|until| until > now);
+        let allowed =
+            (ejected + 1) * 100 <= config.max_ejection_percent as usize * self.targets.len();
+        if outlier.failures < config.consecutive_failures || ejected_now || !allowed {
+            return None;
+        }
+
+        if outlier.ejected_until.is_some_and(|until| {
+            now - until > TimeDelta::from_std(max_ejection).unwrap_or_default()
+        }) {
+            outlier.ejections = 0;
+        }
+        outlier.ejections += 1;
+        outlier.failures = 0;
+        let ejection = Duration::from_millis(config.base_ejection_ms)
+            .saturating_mul(outlier.ejections)
+            .min(max_ejection);
+        outlier.ejected_until = Some(now + TimeDelta::from_std(ejection).unwrap_or_default());
+        Some(ejection)
+    }
+
+    fn round_robin(&self, available: &[usize]) -> usize {
+        available[self.next.fetch_add(1, Ordering::Relaxed) % available.len()]
+    }
+
+    /// Smooth weighted round-robin: each turn every available target gains
+    /// its weight and the one with the most goes next, paying back the
+    /// total. A heavy target's turns are spread out rather than back to
+    /// back, and unavailable targets neither gain nor lose credit.
+    fn weighted_round_robin(&self, available: &[usize]) -> usize {
+        let mut credits = self.credits.lock().unwrap();
+        let mut total = 0;
+        for &i in available {
+            let weight = i64::from(self.targets[i].weight);
+            credits[i] += weight;
+            total += weight;
+        }
+        let best = available
+            .iter()
+            .copied()
+            .max_by_key(|&i| (credits[i], Reverse(i)))
+            .unwrap_or(available[0]);
+        credits[best] -= total;
+        best
+    }
+}
+
+impl UpstreamRegistry {
+    pub fn get(&self, url: &str) -> Arc<UpstreamState> {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        upstreams.entry(url.to_string()).or_default().clone()
+    }
+
+    pub fn all(&self) -> Vec<(String, Arc<UpstreamState>)> {
+        let upstreams = self.upstreams.lock().unwrap();
+        upstreams
+            .iter()
+            .map(|(url, state)| (url.clone(), state.clone()))
+            .collect()
+    }
+}
+
+impl UpstreamState {
+    /// Whether requests may be sent to the upstream as far as health checks
+    /// and outlier detection go. Its circuit breaker only counts for routes
+    /// that have one.
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && self.ejected_until().is_none()
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    /// End of the upstream's current ejection, if it is ejected
+    pub fn ejected_until(&self) -> Option<DateTime<Utc>> {
+        let outlier = self.outlier.lock().unwrap();
+        outlier.ejected_until.filter(|&until| until > Utc::now())
+    }
+
+    pub fn outstanding(&self) -> u64 {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        !self.health.lock().unwrap().unhealthy
+    }
+
+    pub fn last_checked(&self) -> Option<DateTime<Utc>> {
+        self.health.lock().unwrap().last_checked
+    }
+
+    /// Records a health probe, flipping the upstream to unhealthy after
+    /// `fall` failures in a row or back to healthy after `rise` passes.
+    /// Returns the new health when it flips.
+    pub fn record_probe(&self, passed: bool, rise: u32, fall: u32) -> Option<bool> {
+        let mut health = self.health.lock().unwrap();
+        health.last_checked = Some(Utc::now());
+        // A probe that agrees with the current health breaks any streak
+        // towards flipping it
+        if passed != health.unhealthy {
+            health.streak = 0;
+            return None;
+        }
+        health.streak += 1;
+        let threshold = if health.unhealthy { rise } else { fall };
+        if health.streak < threshold {
+            return None;
+        }
+        health.unhealthy = !health.unhealthy;
+        health.streak = 0;
+        Some(!health.unhealthy)
+    }
+
+    /// Forgets the health check results, for upstreams no longer checked
+    pub fn reset_health(&self) {
+        *self.health.lock().unwrap() = Health::default();
+    }
+}
+
+impl InFlight {
+    pub fn new(upstream: Arc<UpstreamState>) -> Self {
+        upstream.outstanding.fetch_add(1, Ordering::Relaxed);
+        Self(upstream)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn hash_ring(targets: &[Upstream]) -> Vec<(u64, usize)> {
+    let mut ring: Vec<(u64, usize)> = targets
+        .iter()
+        .enumerate()
+        .flat_map(|(index, target)| {
+            let points = target
+                .weight
+                .saturating_mul(RING_POINTS_PER_WEIGHT)
+                .min(MAX_RING_POINTS);
+            (0..points).map(move |point| {
+                let mut bytes = target.url.as_bytes().to_vec();
+                bytes.extend_from_slice(&point.to_le_bytes());
+                (hash(&bytes), index)
+            })
+        })
+        .collect();
+    ring.sort_unstable();
+    ring
+}
+
+fn hash_key(key: &HashKey, headers: &HeaderMap, client_ip: IpAddr) -> Option<String> {
+    match key {
+        HashKey::Header(name) => headers.get(name)?.to_str().ok().map(str::to_string),
+        HashKey::Cookie(name) => headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(cookie, _)| cookie == name)
+            .map(|(_, value)| value.to_string()),
+        HashKey::ClientIp => Some(client_ip.to_string()),
+    }
+}
+
+/// Hash for the ring that stays the same across Rust releases and
+/// platforms, so keys keep going to the same upstream after an upgrade:
+/// FNV-1a, with its bits mixed by the MurmurHash3 finalizer since FNV alone
+/// spreads inputs that differ only at the end poorly
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = fnv1a(bytes);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn balancer(strategy: LoadBalancing, weights: &[u32]) -> Balancer {
+        Balancer::new(&Route {
+            upstreams: weights
+                .iter()
+                .enumerate()
+                .map(|(i, &weight)| Upstream {
+                    url: format!("http://backend{}.internal", i),
+                    weight,
+                })
+                .collect(),
+            load_balancing: Some(strategy),
+            ..Default::default()
+        })
+    }
+
+    fn picks(balancer: &Balancer, registry: &UpstreamRegistry, headers: &HeaderMap) -> Vec<String> {
+        (0..6)
+            .map(|_| balancer.pick(registry, headers, IP).unwrap().url.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let balancer = balancer(LoadBalancing::RoundRobin, &[1, 1, 1]);
+        let picked = picks(&balancer, &UpstreamRegistry::default(), &HeaderMap::new());
+        assert_eq!(picked[0], "http://backend0.internal");
+        assert_eq!(picked[1], "http://backend1.internal");
+        assert_eq!(picked[3], picked[0]);
+    }
+
+    #[test]
+    fn test_weighted_round_robin_interleaves() {
+        let registry = UpstreamRegistry::default();
+        let turns = |weights: &[u32], count: usize| {
+            let balancer = balancer(LoadBalancing::WeightedRoundRobin, weights);
+            (0..count)
+                .map(|_| {
+                    let url = &balancer.pick(&registry, &HeaderMap::new(), IP).unwrap().url;
+                    url["http://backend".len()..].chars().next().unwrap()
+                })
+                .collect::<String>()
+        };
+        assert_eq!(turns(&[5, 1, 1], 7), "0010200");
+        assert_eq!(turns(&[1, 2], 3), "101");
+        // Huge weights cost nothing up front
+        assert_eq!(turns(&[u32::MAX, 1], 3), "000");
+    }
+
+    #[test]
+    fn test_hash_ring_is_stable_and_bounded() {
+        // FNV-1a test vectors, so the ring can't change between releases
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+
+        let ring = hash_ring(&[
+            Upstream {
+                url: "http://a.internal".to_string(),
+                weight: u32::MAX,
+            },
+            Upstream {
+                url: "http://b.internal".to_string(),
+                weight: 1,
+            },
+        ]);
+        assert_eq!(
+            ring.len(),
+            (MAX_RING_POINTS + RING_POINTS_PER_WEIGHT) as usize
+        );
+    }
+
+    #[test]
+    fn test_least_outstanding() {
+        let (balancer, registry) = (
+            balancer(LoadBalancing::LeastOutstanding, &[1, 1]),
+            UpstreamRegistry::default(),
+        );
+        let _busy = InFlight::new(registry.get("http://backend0.internal"));
+        for url in picks(&balancer, &registry, &HeaderMap::new()) {
+            assert_eq!(url, "http://backend1.internal");
+        }
+    }
+
+    #[test]
+    fn test_random_two_choices_avoids_busiest() {
+        let (balancer, registry) = (
+            balancer(LoadBalancing::RandomTwoChoices, &[1, 1]),
+            UpstreamRegistry::default(),
+        );
+        let _busy = InFlight::new(registry.get("http://backend1.internal"));
+        for url in picks(&balancer, &registry, &HeaderMap::new()) {
+            assert_eq!(url, "http://backend0.internal");
+        }
+    }
+
+    #[test]
+    fn test_unhealthy_upstreams_are_skipped() {
+        let registry = UpstreamRegistry::default();
+        let unhealthy = registry.get("http://backend1.internal");
+        assert_eq!(unhealthy.record_probe(false, 2, 2), None);
+        assert_eq!(unhealthy.record_probe(false, 2, 2), Some(false));
+
+        for strategy in [
+            LoadBalancing::RoundRobin,
+            LoadBalancing::WeightedRoundRobin,
+            LoadBalancing::LeastOutstanding,
+            LoadBalancing::RandomTwoChoices,
+            LoadBalancing::ConsistentHash {
+                key: HashKey::ClientIp,
+            },
+        ] {
+            let balancer = balancer(strategy, &[1, 1]);
+            for url in picks(&balancer, &registry, &HeaderMap::new()) {
+                assert_eq!(url, "http://backend0.internal");
+            }
+        }
+
+        registry
+            .get("http://backend0.internal")
+            .record_probe(false, 1, 1);
+        let balancer = balancer(LoadBalancing::RoundRobin, &[1, 1]);
+        assert!(balancer.pick(&registry, &HeaderMap::new(), IP).is_none());
+
+        assert_eq!(unhealthy.record_probe(true, 2, 2), None);
+        assert_eq!(unhealthy.record_probe(false, 2, 2), None);
+        assert_eq!(unhealthy.record_probe(true, 2, 2), None);
+        assert_eq!(unhealthy.record_probe(true, 2, 2), Some(true));
+    }
+
+    #[test]
+    fn test_outlier_ejection() {
+        let (balancer, registry) = (
+            balancer(LoadBalancing::RoundRobin, &[1, 1, 1, 1]),
+            UpstreamRegistry::default(),
+        );
+        let config = OutlierDetectionConfig {
+            consecutive_failures: 2,
+            base_ejection_ms: 1_000,
+            max_ejection_ms: 2_500,
+            max_ejection_percent: 50,
+        };
+        let url = |i: usize| format!("http://backend{}.internal", i);
+        let record =
+            |i: usize, failed: bool| balancer.record_outcome(&registry, &url(i), failed, &config);
+
+        assert_eq!(record(0, true), None);
+        assert_eq!(record(0, false), None);
+        assert_eq!(record(0, true), None);
+        assert_eq!(record(0, true), Some(Duration::from_secs(1)));
+        assert!(!registry.get(&url(0)).is_available());
+
+        // Ejection time grows with each repeat, up to the maximum
+        for expected in [2_000, 2_500] {
+            registry.get(&url(0)).outlier.lock().unwrap().ejected_until = Some(Utc::now());
+            record(0, true);
+            assert_eq!(record(0, true), Some(Duration::from_millis(expected)));
+        }
+
+        // Only half of the upstreams may be ejected at once
+        record(1, true);
+        assert!(record(1, true).is_some());
+        record(2, true);
+        assert_eq!(record(2, true), None);
+        assert!(registry.get(&url(2)).is_available());
+        for _ in 0..4 {
+            assert_ne!(
+                balancer.pick(&registry, &HeaderMap::new(), IP).unwrap().url,
+                url(0)
+            );
+        }
+    }
+
+    #[test]
+    fn test_consistent_hash_sticks_to_key() {
+        let balancer = balancer(
+            LoadBalancing::ConsistentHash {
+                key: HashKey::Cookie("session".to_string()),
+            },
+            &[1, 1, 1],
+        );
+        let registry = UpstreamRegistry::default();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, "theme=dark; session=abc123".parse().unwrap());
+        let picked = picks(&balancer, &registry, &headers);
+        assert!(picked.iter().all(|url| url == &picked[0]));
+
+        let spread: std::collections::HashSet<String> = (0..50)
+            .map(|i| {
+                let mut headers = HeaderMap::new();
+                headers.insert(COOKIE, format!("session=user{}", i).parse().unwrap());
+                balancer.pick(&registry, &headers, IP).unwrap().url.clone()
+            })
+            .collect();
+        assert_eq!(spread.len(), 3);
+    }
+}
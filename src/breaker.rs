@@ -0,0 +1,215 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{config::CircuitBreakerConfig, models::CircuitState};
+
+/// Buckets the rolling window is split into
+const WINDOW_BUCKETS: u32 = 10;
+
+/// Closed/open/half-open circuit breaker for one upstream. While open,
+/// requests fail fast instead of waiting on an upstream that is down.
+#[derive(Default)]
+pub struct CircuitBreaker {
+    inner: Mutex<Breaker>,
+}
+
+#[derive(Default)]
+struct Breaker {
+    state: State,
+    /// Request counts over the rolling window, oldest first
+    buckets: VecDeque<Bucket>,
+}
+
+#[derive(Default)]
+enum State {
+    #[default]
+    Closed,
+    Open {
+        until: Instant,
+    },
+    /// Letting `permits` more trial requests through. If a trial never
+    /// reports back, e.g. because the client went away, more are allowed
+    /// after `until`.
+    HalfOpen {
+        permits: u32,
+        successes: u32,
+        until: Instant,
+    },
+}
+
+struct Bucket {
+    start: Instant,
+    requests: u32,
+    failures: u32,
+}
+
+impl CircuitBreaker {
+    pub fn state(&self) -> CircuitState {
+        match self.inner.lock().unwrap().state {
+            State::Closed => CircuitState::Closed,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Whether `admit` would let a request through right now
+    pub fn allows(&self) -> bool {
+        let now = Instant::now();
+        match self.inner.lock().unwrap().state {
+            State::Closed => true,
+            State::Open { until } => now >= until,
+            State::HalfOpen { permits, until, .. } => permits > 0 || now >= until,
+        }
+    }
+
+    /// Decides whether a request may go to the upstream, moving an open
+    /// breaker to half-open once `open_ms` has passed. Returns the decision
+    /// and the state the breaker moved to, if it changed.
+    pub fn admit(&self, config: &CircuitBreakerConfig) -> (bool, Option<CircuitState>) {
+        let now = Instant::now();
+        let retry_after = now + Duration::from_millis(config.open_ms);
+        let mut breaker = self.inner.lock().unwrap();
+        match &mut breaker.state {
+            State::Closed => (true, None),
+            State::Open { until } if now < *until => (false, None),
+            State::Open { .. } => {
+                breaker.state = State::HalfOpen {
+                    permits: config.half_open_requests - 1,
+                    successes: 0,
+                    until: retry_after,
+                };
+                (true, Some(CircuitState::HalfOpen))
+            }
+            State::HalfOpen { permits, until, .. } => {
+                if *permits == 0 && now >= *until {
+                    *permits = config.half_open_requests;
+                    *until = retry_after;
+                }
+                if *permits == 0 {
+                    return (false, None);
+                }
+                *permits -= 1;
+                (true, None)
+            }
+        }
+    }
+
+    /// Records whether a request to the upstream failed. Returns the state
+    /// the breaker moved to, if it changed.
+    pub fn record(&self, failed: bool, config: &CircuitBreakerConfig) -> Option<CircuitState> {
+        let now = Instant::now();
+        let open = State::Open {
+            until: now + Duration::from_millis(config.open_ms),
+        };
+        let mut breaker = self.inner.lock().unwrap();
+        match &mut breaker.state {
+            State::Closed => {
+                let (requests, failures) = breaker.count(now, failed, config);
+                if requests < config.min_requests
+                    || u64::from(failures) * 100
+                        < u64::from(config.failure_rate_percent) * u64::from(requests)
+                {
+                    return None;
+                }
+                breaker.state = open;
+                breaker.buckets.clear();
+                Some(CircuitState::Open)
+            }
+            // Requests let through before the breaker opened
+            State::Open { .. } => None,
+            State::HalfOpen { .. } if failed => {
+                breaker.state = open;
+                Some(CircuitState::Open)
+            }
+            State::HalfOpen { successes, .. } => {
+                *successes += 1;
+                if *successes < config.half_open_requests {
+                    return None;
+                }
+                breaker.state = State::Closed;
+                Some(CircuitState::Closed)
+            }
+        }
+    }
+}
+
+impl Breaker {
+    /// Adds a request to the rolling window, returning the window's
+    /// request and failure totals
+    fn count(&mut self, now: Instant, failed: bool, config: &CircuitBreakerConfig) -> (u32, u32) {
+        let window = Duration::from_millis(config.window_ms);
+        let width = window / WINDOW_BUCKETS;
+        while self
+            .buckets
+            .front()
+            .is_some_and(|bucket| now.duration_since(bucket.start) >= window)
+        {
+            self.buckets.pop_front();
+        }
+        if self
+            .buckets
+            .back()
+            .is_none_or(|bucket| now.duration_since(bucket.start) >= width)
+        {
+            self.buckets.push_back(Bucket {
+                start: now,
+                requests: 0,
+                failures: 0,
+            });
+        }
+
+        let bucket = self.buckets.back_mut().unwrap();
+        bucket.requests += 1;
+        bucket.failures += u32::from(failed);
+        self.buckets
+            .iter()
+            .fold((0, 0), |(requests, failures), bucket| {
+                (requests + bucket.requests, failures + bucket.failures)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CircuitBreakerConfig {
+        serde_json::from_str(r#"{"min_requests": 4, "open_ms": 20, "half_open_requests": 2}"#)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_opens_on_failure_rate() {
+        let (breaker, config) = (CircuitBreaker::default(), config());
+        assert_eq!(breaker.record(true, &config), None);
+        assert_eq!(breaker.record(false, &config), None);
+        assert_eq!(breaker.record(false, &config), None);
+        assert_eq!(breaker.record(true, &config), Some(CircuitState::Open));
+        assert!(!breaker.allows());
+        assert_eq!(breaker.admit(&config), (false, None));
+    }
+
+    #[test]
+    fn test_half_open_trials() {
+        let (breaker, config) = (CircuitBreaker::default(), config());
+        for _ in 0..4 {
+            breaker.record(true, &config);
+        }
+
+        std::thread::sleep(Duration::from_millis(25));
+        assert_eq!(breaker.admit(&config), (true, Some(CircuitState::HalfOpen)));
+        assert_eq!(breaker.admit(&config), (true, None));
+        assert_eq!(breaker.admit(&config), (false, None));
+        assert_eq!(breaker.record(true, &config), Some(CircuitState::Open));
+
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.admit(&config).0);
+        assert!(breaker.admit(&config).0);
+        assert_eq!(breaker.record(false, &config), None);
+        assert_eq!(breaker.record(false, &config), Some(CircuitState::Closed));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,372 @@
+use std::{fmt::Write, net::SocketAddr, path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use http::{Request, header::HOST};
+
+use crate::{
+    config::{ValueMatch, load_config},
+    header_rules::HeaderVars,
+    router::{RouteTable, shadowed_routes},
+};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Reverse proxy with routing, rate limiting and live monitoring"
+)]
+pub struct Cli {
+    /// Path to the gateway config file
+    #[arg(long, short, global = true, default_value = "config.json")]
+    pub config: PathBuf,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the gateway (the default when no subcommand is given)
+    Serve(ServeArgs),
+    /// Check the config file and exit non-zero if it is invalid
+    Validate,
+    /// Print the routing table resolved from the config file
+    Routes,
+    /// Show which route a request hits and where it would be forwarded
+    Match(MatchArgs),
+}
+
+#[derive(clap::Args, Clone, Default)]
+pub struct ServeArgs {
+    /// Address to accept proxied traffic on, overriding `server.bind`
+    #[arg(long)]
+    pub bind: Option<SocketAddr>,
+
+    /// Serve the monitoring and admin API on a separate address, overriding
+    /// `server.admin_bind`
+    #[arg(long)]
+    pub admin_bind: Option<SocketAddr>,
+}
+
+#[derive(clap::Args)]
+pub struct MatchArgs {
+    /// Request path, optionally with a query string, e.g. /bin/get?a=1
+    pub path: String,
+
+    /// Host the request is addressed to
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Request method
+    #[arg(long, short = 'X', default_value = "GET")]
+    pub method: String,
+
+    /// Request header as `name: value`, may be repeated
+    #[arg(long = "header", short = 'H')]
+    pub headers: Vec<String>,
+}
+
+/// Loads the routing table for the offline subcommands, printing any config
+/// errors to stderr.
+fn load_table(cli: &Cli) -> Result<RouteTable, ExitCode> {
+    load_config(&cli.config).map(RouteTable::new).map_err(|e| {
+        eprintln!("{}", e);
+        ExitCode::FAILURE
+    })
+}
+
+pub fn validate(cli: &Cli) -> ExitCode {
+    match load_table(cli) {
+        Ok(table) => {
+            println!(
+                "{} is valid ({} routes)",
+                cli.config.display(),
+                table.routes().len()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(code) => code,
+    }
+}
+
+pub fn print_routes(cli: &Cli) -> ExitCode {
+    let table = match load_table(cli) {
+        Ok(table) => table,
+        Err(code) => return code,
+    };
+    print!("{}", format_routes(&table));
+    ExitCode::SUCCESS
+}
+
+/// The routing table as aligned columns, followed by a warning for each
+/// route another one makes unreachable
+fn format_routes(table: &RouteTable) -> String {
+    let rows: Vec<[String; 7]> = table
+        .routes()
+        .iter()
+        .map(|r| {
+            let backends: Vec<String> = r.targets().into_iter().map(|t| t.url).collect();
+            let predicates: Vec<String> = r
+                .headers
+                .iter()
+                .map(|header| format_value_match("", header))
+                .chain(r.query.iter().map(|param| format_value_match("?", param)))
+                .collect();
+            [
+                r.id.clone(),
+                r.host.clone().unwrap_or_else(|| "*".to_string()),
+                if r.methods.is_empty() {
+                    "*".to_string()
+                } else {
+                    r.methods.join(",")
+                },
+                r.path.clone(),
+                if predicates.is_empty() {
+                    "-".to_string()
+                } else {
+                    predicates.join(" ")
+                },
+                r.priority().to_string(),
+                backends.join(", "),
+            ]
+        })
+        .collect();
+    let header = [
+        "ID", "HOST", "METHODS", "PATH", "MATCH", "PRIORITY", "BACKEND",
+    ]
+    .map(String::from);
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].len())
+                .chain([header[column].len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let mut out = String::new();
+    for row in [header].iter().chain(&rows) {
+        let (last, columns) = row.split_last().unwrap();
+        for (value, width) in columns.iter().zip(&widths) {
+            write!(out, "{:width$}  ", value).unwrap();
+        }
+        writeln!(out, "{}", last).unwrap();
+    }
+
+    let routes = table.routes();
+    for (shadowed, by) in shadowed_routes(routes) {
+        let (shadowed, by) = (&routes[shadowed], &routes[by]);
+        writeln!(
+            out,
+            "warning: route `{}` ({}) is unreachable: every path it matches goes to route `{}` ({})",
+            shadowed.id, shadowed.path, by.id, by.path
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// A header or query predicate as `name=value`, `name~regex` or just `name`
+fn format_value_match(prefix: &str, matcher: &ValueMatch) -> String {
+    match (&matcher.value, &matcher.regex) {
+        (Some(value), _) => format!("{}{}={}", prefix, matcher.name, value),
+        (None, Some(regex)) => format!("{}{}~{}", prefix, matcher.name, regex),
+        (None, None) => format!("{}{}", prefix, matcher.name),
+    }
+}
+
+pub fn print_match(cli: &Cli, args: &MatchArgs) -> ExitCode {
+    let table = match load_table(cli) {
+        Ok(table) => table,
+        Err(code) => return code,
+    };
+    match describe_match(&table, args) {
+        Ok(description) => {
+            print!("{}", description);
+            ExitCode::SUCCESS
+        }
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// What `match` prints for a request, or why it failed
+fn describe_match(table: &RouteTable, args: &MatchArgs) -> Result<String, String> {
+    let mut req = Request::builder()
+        .method(args.method.as_str())
+        .uri(args.path.as_str());
+    if let Some(host) = &args.host {
+        req = req.header(HOST, host);
+    }
+    for header in &args.headers {
+        let (name, value) = header.split_once(':').unwrap_or((header, ""));
+        req = req.header(name.trim(), value.trim());
+    }
+    let req = req
+        .body(())
+        .map_err(|e| format!("invalid request: {}", e))?;
+
+    let matched = table
+        .match_route(&req)
+        .or_else(|| table.default_match(req.uri().path()))
+        .ok_or_else(|| format!("no route matches {} {}", req.method(), req.uri()))?;
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "route:   {} ({})",
+        matched.route.id, matched.route.path
+    )
+    .unwrap();
+    for (name, value) in &matched.params {
+        writeln!(out, "  {} = {}", name, value).unwrap();
+    }
+    for upstream in matched.balancer().targets() {
+        writeln!(
+            out,
+            "backend: {}",
+            matched.backend_uri(&upstream.url, req.uri().query())
+        )
+        .unwrap();
+    }
+    // Values only known once a request comes in are left as placeholders
+    let vars = HeaderVars {
+        client_ip: "{client_ip}",
+        route: &matched.route.id,
+        request_id: "{request_id}",
+    };
+    let mut headers = req.headers().clone();
+    matched.rewrite_request_headers(&mut headers, &vars);
+    for name in headers.keys() {
+        if headers.get_all(name) == req.headers().get_all(name) {
+            continue;
+        }
+        for value in headers.get_all(name) {
+            writeln!(out, "  {}: {}", name, value.to_str().unwrap_or_default()).unwrap();
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GatewayConfig;
+
+    fn table() -> RouteTable {
+        let config: GatewayConfig = serde_json::from_str(
+            r#"{"routes": [
+                {"id": "users", "path": "/users/{id}", "backend_url": "http://users.internal",
+                 "request_headers": {"set": {"X-User": "{id}"}}},
+                {"id": "users-admin", "path": "/users/{id}", "host": "admin.example.com",
+                 "backend_url": "http://admin.internal"},
+                {"id": "users-write", "path": "/users/{id}", "methods": ["POST"],
+                 "backend_url": "http://writer.internal"},
+                {"id": "users-beta", "path": "/users/{id}",
+                 "headers": [{"name": "X-Env", "value": "beta"}],
+                 "backend_url": "http://beta.internal"}
+            ]}"#,
+        )
+        .unwrap();
+        RouteTable::new(config)
+    }
+
+    fn match_args(args: &[&str]) -> MatchArgs {
+        let cli = Cli::try_parse_from([&["gateway-api", "match"], args].concat()).unwrap();
+        match cli.command {
+            Some(Command::Match(args)) => args,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_match_resolution() {
+        let table = table();
+        let first_line = |args: &[&str]| {
+            describe_match(&table, &match_args(args))
+                .unwrap()
+                .lines()
+                .next()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            describe_match(&table, &match_args(&["/users/7/orders?a=1"])).unwrap(),
+            "route:   users (/users/{id})\n  id = 7\nbackend: http://users.internal/orders?a=1\n  x-user: 7\n"
+        );
+        assert_eq!(
+            first_line(&["/users/7", "--host", "Admin.Example.com:8080"]),
+            "route:   users-admin (/users/{id})"
+        );
+        assert_eq!(
+            first_line(&["/users/7", "-X", "POST"]),
+            "route:   users-write (/users/{id})"
+        );
+        assert_eq!(
+            first_line(&["/users/7", "-H", "X-Env: beta"]),
+            "route:   users-beta (/users/{id})"
+        );
+    }
+
+    #[test]
+    fn test_routes_listing() {
+        let config: GatewayConfig = serde_json::from_str(
+            r#"{"routes": [
+                {"id": "catch-all", "path": "/", "priority": 10, "backend_url": "http://all.internal"},
+                {"id": "search", "path": "/search", "methods": ["GET", "HEAD"],
+                 "query": [{"name": "q"}, {"name": "v", "value": "2"}],
+                 "backend_url": "http://search.internal"},
+                {"id": "admin", "host": "admin.example.com", "path": "/search",
+                 "headers": [{"name": "X-Env", "regex": "^beta"}],
+                 "backend_url": "http://admin.internal"}
+            ]}"#,
+        )
+        .unwrap();
+        let listing = format_routes(&RouteTable::new(config));
+
+        assert_eq!(
+            listing,
+            "\
+ID         HOST               METHODS   PATH     MATCH        PRIORITY  BACKEND
+catch-all  *                  *         /        -            10        http://all.internal
+search     *                  GET,HEAD  /search  ?q ?v=2      0         http://search.internal
+admin      admin.example.com  *         /search  X-Env~^beta  0         http://admin.internal
+warning: route `search` (/search) is unreachable: every path it matches goes to route `catch-all` (/)
+"
+        );
+    }
+
+    #[test]
+    fn test_match_without_route_fails() {
+        let path =
+            std::env::temp_dir().join(format!("gateway-cli-{:08x}.json", rand::random::<u32>()));
+        std::fs::write(
+            &path,
+            r#"{"routes": [{"path": "/api", "backend_url": "http://api.internal"}]}"#,
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from([
+            "gateway-api",
+            "--config",
+            path.to_str().unwrap(),
+            "match",
+            "/nope",
+            "-X",
+            "DELETE",
+        ])
+        .unwrap();
+        let Some(Command::Match(args)) = &cli.command else {
+            unreachable!();
+        };
+
+        assert_eq!(print_match(&cli, args), ExitCode::FAILURE);
+        assert_eq!(
+            describe_match(&table(), args),
+            Err("no route matches DELETE /nope".to_string())
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
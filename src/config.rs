@@ -1,18 +1,1685 @@
-use crate::state::Route;
-use serde_json::Value;
-use std::fs;
-
-pub fn extract_routes() -> Vec<Route> {
-    let config = fs::read_to_string("config.json").expect("Couldn't read config file");
-    let config: Value = serde_json::from_str(config.as_str()).unwrap();
-    let routes = config["routes"].as_array().unwrap();
-
-    routes
-        .iter()
-        .map(|v| Route {
-            path: v["path"].as_str().unwrap_or_default().to_string(),
-            backend_url: v["backend_url"].as_str().unwrap_or_default().to_string(),
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    net::SocketAddr,
+    path::Path,
+    time::Duration,
+};
+
+use http::{HeaderName, Method};
+use regex::Regex;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{ConfigError, ConfigIssue},
+    forwarded::parse_proxy,
+    header_rules::HEADER_VARS,
+    router::PathPattern,
+    template::Template,
+};
+
+/// Top-level layout of `config.json`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayConfig {
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub upstream: UpstreamConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub forwarded: ForwardedConfig,
+    #[serde(default, skip_serializing_if = "TimeoutConfig::is_empty")]
+    pub timeouts: TimeoutConfig,
+    /// Backend for requests no route matches. The request path is forwarded
+    /// unchanged. Without one those requests get a 404.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_backend: Option<String>,
+    pub routes: Vec<Route>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the gateway listens on
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    /// Serve the monitoring and admin API on its own address instead of
+    /// alongside proxied traffic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_bind: Option<String>,
+    /// Host whose routes serve requests for hosts no route names
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_host: Option<String>,
+    /// Write routes changed through the admin API back to the config file
+    #[serde(default)]
+    pub persist_route_changes: bool,
+    /// Bearer token the admin API requires to change routes. Routes can't
+    /// be changed at runtime without one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Seconds between replenishing one request from the quota
+    #[serde(default = "default_per_second")]
+    pub per_second: u64,
+    #[serde(default = "default_burst_size")]
+    pub burst_size: u32,
+}
+
+/// Settings for the HTTP client used to reach backends
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_max_idle_per_host: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_idle_timeout_ms: Option<u64>,
+}
+
+/// Body size limits in bytes for routes that don't set their own. No limit
+/// when omitted.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_request_body: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_response_body: Option<u64>,
+}
+
+/// Headers telling backends who a request came from: `Forwarded`,
+/// `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host` and
+/// `X-Real-IP`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardedConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// CIDR blocks or addresses of proxies in front of the gateway. Their
+    /// forwarding headers are kept and appended to; anyone else's are
+    /// replaced.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_proxies: Vec<String>,
+}
+
+/// Upstream timeouts in milliseconds, set globally and overridden field by
+/// field per route
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Time to establish the connection to the backend, 10s when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_ms: Option<u64>,
+    /// Time for the whole exchange, including retries and the response
+    /// body. No limit when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_ms: Option<u64>,
+    /// Time to wait for the next read from the backend, 60s when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_idle_ms: Option<u64>,
+    /// Time to wait for the next read from the backend when its response is
+    /// an event stream, 5 minutes when omitted. Used instead of
+    /// `read_idle_ms` and `total_ms` once the response has started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_idle_ms: Option<u64>,
+    /// Time a WebSocket tunnel may go without a message in either
+    /// direction, 5 minutes when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub websocket_idle_ms: Option<u64>,
+    /// Time a WebSocket tunnel may stay open. No limit when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub websocket_lifetime_ms: Option<u64>,
+}
+
+impl TimeoutConfig {
+    pub fn is_empty(&self) -> bool {
+        self.fields().iter().all(|(_, value)| value.is_none())
+    }
+
+    fn fields(&self) -> [(&'static str, Option<u64>); 6] {
+        [
+            ("connect_ms", self.connect_ms),
+            ("total_ms", self.total_ms),
+            ("read_idle_ms", self.read_idle_ms),
+            ("stream_idle_ms", self.stream_idle_ms),
+            ("websocket_idle_ms", self.websocket_idle_ms),
+            ("websocket_lifetime_ms", self.websocket_lifetime_ms),
+        ]
+    }
+}
+
+/// Timeouts in effect for one route
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub total: Option<Duration>,
+    pub read_idle: Duration,
+    pub stream_idle: Duration,
+    pub websocket_idle: Duration,
+    pub websocket_lifetime: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Stable name used by the admin API, derived from `path` when omitted
+    #[serde(default)]
+    pub id: String,
+    /// Only match requests for this host, either exact (`api.example.com`)
+    /// or a wildcard subdomain (`*.example.com`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    pub path: String,
+    /// The backend, for routes with only one. Set `upstreams` instead to
+    /// balance requests across several.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub backend_url: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstreams: Vec<Upstream>,
+    /// How requests are spread over `upstreams`, round-robin when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_balancing: Option<LoadBalancing>,
+    /// Probe the route's upstreams and stop sending requests to those that
+    /// fail
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheckConfig>,
+    /// Take upstreams out of rotation for a while when they keep failing
+    /// real requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    /// Fail fast instead of sending requests to upstreams whose requests
+    /// are mostly failing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// The upstreams speak gRPC, so they are reached over HTTP/2 (prior
+    /// knowledge for `http://` URLs) and the path is kept as is
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub grpc: bool,
+    /// Routes with a higher priority win over more specific ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    /// Only match these HTTP methods; any method when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    /// Request headers that must all match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<ValueMatch>,
+    /// Query parameters that must all match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub query: Vec<ValueMatch>,
+    /// How the request path is turned into the upstream path, `strip_prefix`
+    /// when omitted, or `preserve` for gRPC routes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<Rewrite>,
+    /// Changes made to the request headers sent upstream
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub request_headers: HeaderRules,
+    /// Changes made to the upstream's response headers
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub response_headers: HeaderRules,
+    /// Largest request body in bytes, overriding `limits.max_request_body`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_request_body: Option<u64>,
+    /// Largest response body in bytes, overriding `limits.max_response_body`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_response_body: Option<u64>,
+    /// Overrides for the global `timeouts`
+    #[serde(default, skip_serializing_if = "TimeoutConfig::is_empty")]
+    pub timeouts: TimeoutConfig,
+    /// Retry failed requests; no retries when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Upstream {
+    pub url: String,
+    /// Share of requests relative to the route's other upstreams, for the
+    /// weighted strategies
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case", deny_unknown_fields)]
+pub enum LoadBalancing {
+    RoundRobin,
+    WeightedRoundRobin,
+    /// The upstream with the fewest requests in flight
+    LeastOutstanding,
+    /// The less loaded of two upstreams picked at random
+    RandomTwoChoices,
+    /// The same upstream for every request with the same key, as long as
+    /// the upstreams don't change
+    ConsistentHash {
+        key: HashKey,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    Header(String),
+    Cookie(String),
+    ClientIp,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// Path requested from each upstream
+    #[serde(default = "default_health_path")]
+    pub path: String,
+    #[serde(default = "default_health_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default = "default_health_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Statuses that count as a passing probe
+    #[serde(default = "default_expected_status")]
+    pub expected_status: Vec<u16>,
+    /// Passing probes in a row that bring an unhealthy upstream back
+    #[serde(default = "default_rise")]
+    pub rise: u32,
+    /// Failing probes in a row that take a healthy upstream out
+    #[serde(default = "default_fall")]
+    pub fall: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutlierDetectionConfig {
+    /// 5xx responses or connection errors in a row that eject an upstream
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32,
+    /// Time out of rotation for the first ejection, multiplied by the
+    /// number of times the upstream has been ejected recently
+    #[serde(default = "default_base_ejection_ms")]
+    pub base_ejection_ms: u64,
+    #[serde(default = "default_max_ejection_ms")]
+    pub max_ejection_ms: u64,
+    /// Most of the route's upstreams that may be ejected at once
+    #[serde(default = "default_max_ejection_percent")]
+    pub max_ejection_percent: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Percentage of failed requests over `window_ms` that opens the breaker
+    #[serde(default = "default_failure_rate_percent")]
+    pub failure_rate_percent: u32,
+    #[serde(default = "default_window_ms")]
+    pub window_ms: u64,
+    /// Requests needed in the window before the failure rate counts
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u32,
+    /// Time the breaker stays open before letting trial requests through
+    #[serde(default = "default_open_ms")]
+    pub open_ms: u64,
+    /// Trial requests that must all succeed to close the breaker again
+    #[serde(default = "default_half_open_requests")]
+    pub half_open_requests: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Attempts including the first one
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+    /// Methods that may be retried, the idempotent ones when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    /// Backend response statuses that are retried
+    #[serde(default = "default_retry_on_status")]
+    pub on_status: Vec<u16>,
+    /// Failures to reach the backend that are retried
+    #[serde(default = "default_retry_on_errors")]
+    pub on_errors: Vec<RetryOn>,
+    /// Delay before the first retry, doubled for each one after it
+    #[serde(default = "default_backoff_base_ms")]
+    pub backoff_base_ms: u64,
+    #[serde(default = "default_backoff_max_ms")]
+    pub backoff_max_ms: u64,
+    /// Picks each delay at random up to its full length, so clients that
+    /// failed together don't retry together
+    #[serde(default = "default_true")]
+    pub jitter: bool,
+    /// Retries allowed as a percentage of the route's recent requests
+    #[serde(default = "default_budget_percent")]
+    pub budget_percent: u32,
+    /// Retries allowed regardless of `budget_percent`, so quiet routes can
+    /// still retry
+    #[serde(default = "default_min_retries_per_second")]
+    pub min_retries_per_second: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// Couldn't connect to the backend
+    Connect,
+    /// Hit a connect or read-idle timeout
+    Timeout,
+    /// The connection failed after the request was sent
+    Reset,
+}
+
+impl RetryConfig {
+    /// Whether requests with `method` may be retried
+    pub fn allows_method(&self, method: &Method) -> bool {
+        if self.methods.is_empty() {
+            method.is_idempotent()
+        } else {
+            self.methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Rewrite {
+    /// Drop the part of the path the route matched
+    StripPrefix,
+    /// Forward the request path unchanged
+    Preserve,
+    /// Replace the part of the path the route matched with `prefix`
+    ReplacePrefix { prefix: String },
+    /// Replace the part of the path the route matched with a template over
+    /// the path's `{name}` captures
+    Template { path: String },
+    /// Replace every match of `pattern` in the whole path, with `$1` or
+    /// `${name}` referring to the pattern's groups
+    Regex {
+        pattern: String,
+        replacement: String,
+    },
+}
+
+/// Changes made to the headers of a proxied request or response, applied
+/// in field order. Values are templates over the path's `{name}` captures
+/// and `{client_ip}`, `{route}` and `{request_id}`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRules {
+    /// Headers to rename, from the old name to the new one
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rename: BTreeMap<String, String>,
+    /// Headers to drop
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+    /// Headers to set, replacing any existing value
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, String>,
+    /// Headers to add, keeping any existing values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub add: BTreeMap<String, String>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.rename.is_empty()
+            && self.remove.is_empty()
+            && self.set.is_empty()
+            && self.add.is_empty()
+    }
+}
+
+/// Predicate on a named header or query parameter. With neither `value`
+/// nor `regex` set it only requires the name to be present.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValueMatch {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+}
+
+impl Route {
+    /// The route's backends: `upstreams`, or just `backend_url`
+    pub fn targets(&self) -> Vec<Upstream> {
+        if self.upstreams.is_empty() {
+            vec![Upstream {
+                url: self.backend_url.clone(),
+                weight: default_weight(),
+            }]
+        } else {
+            self.upstreams.clone()
+        }
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority.unwrap_or(0)
+    }
+
+    /// Number of method, header and query predicates
+    pub fn predicate_count(&self) -> usize {
+        usize::from(!self.methods.is_empty()) + self.headers.len() + self.query.len()
+    }
+
+    fn same_predicates(&self, other: &Route) -> bool {
+        let methods = |route: &Route| {
+            let mut methods: Vec<String> = route
+                .methods
+                .iter()
+                .map(|m| m.to_ascii_uppercase())
+                .collect();
+            methods.sort();
+            methods
+        };
+        methods(self) == methods(other)
+            && self.headers == other.headers
+            && self.query == other.query
+    }
+}
+
+impl GatewayConfig {
+    /// Request body limit for `route`, falling back to the global one
+    pub fn max_request_body(&self, route: &Route) -> Option<u64> {
+        route.max_request_body.or(self.limits.max_request_body)
+    }
+
+    /// Response body limit for `route`, falling back to the global one
+    pub fn max_response_body(&self, route: &Route) -> Option<u64> {
+        route.max_response_body.or(self.limits.max_response_body)
+    }
+
+    /// Timeouts for `route`, falling back to the global ones and then the
+    /// built-in defaults
+    pub fn timeouts(&self, route: &Route) -> Timeouts {
+        let (route, global) = (&route.timeouts, &self.timeouts);
+        Timeouts {
+            connect: Duration::from_millis(
+                route
+                    .connect_ms
+                    .or(global.connect_ms)
+                    .unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS),
+            ),
+            total: route
+                .total_ms
+                .or(global.total_ms)
+                .map(Duration::from_millis),
+            read_idle: Duration::from_millis(
+                route
+                    .read_idle_ms
+                    .or(global.read_idle_ms)
+                    .unwrap_or(DEFAULT_READ_IDLE_TIMEOUT_MS),
+            ),
+            stream_idle: Duration::from_millis(
+                route
+                    .stream_idle_ms
+                    .or(global.stream_idle_ms)
+                    .unwrap_or(DEFAULT_STREAM_IDLE_TIMEOUT_MS),
+            ),
+            websocket_idle: Duration::from_millis(
+                route
+                    .websocket_idle_ms
+                    .or(global.websocket_idle_ms)
+                    .unwrap_or(DEFAULT_WEBSOCKET_IDLE_TIMEOUT_MS),
+            ),
+            websocket_lifetime: route
+                .websocket_lifetime_ms
+                .or(global.websocket_lifetime_ms)
+                .map(Duration::from_millis),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: default_bind(),
+            admin_bind: None,
+            default_host: None,
+            persist_route_changes: false,
+            admin_token: None,
+        }
+    }
+}
+
+impl Default for ForwardedConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            per_second: default_per_second(),
+            burst_size: default_burst_size(),
+        }
+    }
+}
+
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_READ_IDLE_TIMEOUT_MS: u64 = 60_000;
+const DEFAULT_STREAM_IDLE_TIMEOUT_MS: u64 = 300_000;
+const DEFAULT_WEBSOCKET_IDLE_TIMEOUT_MS: u64 = 300_000;
+
+/// Largest upstream weight
+const MAX_WEIGHT: u32 = 10_000;
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_health_path() -> String {
+    "/health".to_string()
+}
+
+fn default_health_interval_ms() -> u64 {
+    5_000
+}
+
+fn default_health_timeout_ms() -> u64 {
+    2_000
+}
+
+fn default_expected_status() -> Vec<u16> {
+    vec![200]
+}
+
+fn default_rise() -> u32 {
+    2
+}
+
+fn default_fall() -> u32 {
+    3
+}
+
+fn default_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_base_ejection_ms() -> u64 {
+    30_000
+}
+
+fn default_max_ejection_ms() -> u64 {
+    300_000
+}
+
+fn default_max_ejection_percent() -> u32 {
+    50
+}
+
+fn default_failure_rate_percent() -> u32 {
+    50
+}
+
+fn default_window_ms() -> u64 {
+    10_000
+}
+
+fn default_min_requests() -> u32 {
+    10
+}
+
+fn default_open_ms() -> u64 {
+    30_000
+}
+
+fn default_half_open_requests() -> u32 {
+    1
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_on_status() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+fn default_retry_on_errors() -> Vec<RetryOn> {
+    vec![RetryOn::Connect, RetryOn::Reset]
+}
+
+fn default_backoff_base_ms() -> u64 {
+    25
+}
+
+fn default_backoff_max_ms() -> u64 {
+    1_000
+}
+
+fn default_budget_percent() -> u32 {
+    20
+}
+
+fn default_min_retries_per_second() -> u32 {
+    3
+}
+
+fn default_bind() -> String {
+    "127.0.0.1:3000".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_per_second() -> u64 {
+    2
+}
+
+fn default_burst_size() -> u32 {
+    5
+}
+
+/// Reads, parses and validates the config file at `path`.
+pub fn load_config(path: &Path) -> Result<GatewayConfig, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|e| ConfigError::Read {
+        path: path.display().to_string(),
+        message: e.to_string(),
+    })?;
+    let config = parse_config(&contents).map_err(|issue| ConfigError::Parse {
+        path: path.display().to_string(),
+        issue,
+    })?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Writes `config` to `path`, replacing the file in one step so the config
+/// watcher never sees a half-written file.
+pub fn save_config(path: &Path, config: &GatewayConfig) -> Result<(), ConfigError> {
+    let write_error = |e: std::io::Error| ConfigError::Write {
+        path: path.display().to_string(),
+        message: e.to_string(),
+    };
+    let contents = serde_json::to_string_pretty(config).map_err(|e| ConfigError::Write {
+        path: path.display().to_string(),
+        message: e.to_string(),
+    })?;
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, contents + "\n").map_err(write_error)?;
+    fs::rename(&tmp_path, path).map_err(write_error)
+}
+
+/// Parses the JSON document, reporting the location of the first problem
+/// as a field path such as `routes[1].backend_url`. Missing route ids are
+/// filled in but nothing is validated yet.
+pub fn parse_config(contents: &str) -> Result<GatewayConfig, ConfigIssue> {
+    let deserializer = &mut serde_json::Deserializer::from_str(contents);
+    let mut config: GatewayConfig =
+        serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            let field = e.path().to_string();
+            let message = e.into_inner().to_string();
+            ConfigIssue::new(if field == "." { "config" } else { &field }, message)
+        })?;
+    config.assign_route_ids();
+    Ok(config)
+}
+
+impl GatewayConfig {
+    /// Gives every route without an explicit `id` one based on its host,
+    /// path and methods, e.g. `/v1/users/{id}` becomes `v1-users-id`, `/`
+    /// on `*.example.com` becomes `wildcard.example.com-root` and `/orders`
+    /// for `POST` becomes `orders-post`. Characters ids
+    /// can't hold become '-', and an id that is already taken gets `-2`,
+    /// `-3` and so on appended, so generated ids are always valid and
+    /// unique.
+    fn assign_route_ids(&mut self) {
+        let mut taken: HashSet<String> = self
+            .routes
+            .iter()
+            .filter(|r| !r.id.is_empty())
+            .map(|r| r.id.clone())
+            .collect();
+        for route in self.routes.iter_mut().filter(|r| r.id.is_empty()) {
+            let path: String = route
+                .path
+                .chars()
+                .filter(|c| !matches!(c, '{' | '}' | '*'))
+                .collect();
+            let mut id = id_segment(&path);
+            if id.is_empty() {
+                id = "root".to_string();
+            }
+            if let Some(host) = &route.host {
+                id = format!("{}-{}", id_segment(&host.replace('*', "wildcard")), id);
+            }
+            for method in &route.methods {
+                id = format!("{}-{}", id, id_segment(&method.to_ascii_lowercase()));
+            }
+            let mut unique = id.clone();
+            let mut n = 1;
+            while taken.contains(&unique) {
+                n += 1;
+                unique = format!("{}-{}", id, n);
+            }
+            taken.insert(unique.clone());
+            route.id = unique;
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut issues = Vec::new();
+
+        if let Some(host) = &self.server.default_host
+            && !is_valid_host(host)
+        {
+            issues.push(ConfigIssue::new(
+                "server.default_host",
+                format!("`{}` is not a host name", host),
+            ));
+        }
+
+        let binds = [
+            ("server.bind", Some(&self.server.bind)),
+            ("server.admin_bind", self.server.admin_bind.as_ref()),
+        ];
+        for (field, bind) in binds {
+            if let Some(bind) = bind
+                && bind.parse::<SocketAddr>().is_err()
+            {
+                issues.push(ConfigIssue::new(
+                    field,
+                    format!("`{}` is not a socket address such as 127.0.0.1:3000", bind),
+                ));
+            }
+        }
+
+        if self
+            .server
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| token.trim().is_empty())
+        {
+            issues.push(ConfigIssue::new("server.admin_token", "must not be empty"));
+        }
+
+        if self.rate_limit.per_second == 0 {
+            issues.push(ConfigIssue::new(
+                "rate_limit.per_second",
+                "must be greater than zero",
+            ));
+        }
+        if self.rate_limit.burst_size == 0 {
+            issues.push(ConfigIssue::new(
+                "rate_limit.burst_size",
+                "must be greater than zero",
+            ));
+        }
+
+        validate_timeouts(&self.timeouts, "timeouts", &mut issues);
+
+        for (index, proxy) in self.forwarded.trusted_proxies.iter().enumerate() {
+            if parse_proxy(proxy).is_none() {
+                issues.push(ConfigIssue::new(
+                    format!("forwarded.trusted_proxies[{}]", index),
+                    format!("`{}` is not an IP address or CIDR block", proxy),
+                ));
+            }
+        }
+
+        let mut seen_ids: HashMap<&str, usize> = HashMap::new();
+        let mut seen_paths: HashMap<(Option<String>, String), Vec<usize>> = HashMap::new();
+        for (index, route) in self.routes.iter().enumerate() {
+            let field = |name: &str| format!("routes[{}].{}", index, name);
+
+            validate_timeouts(&route.timeouts, &field("timeouts"), &mut issues);
+            if let Some(retry) = &route.retry {
+                validate_retry(retry, &field("retry"), &mut issues);
+            }
+            if let Some(health_check) = &route.health_check {
+                validate_health_check(health_check, &field("health_check"), &mut issues);
+            }
+            if let Some(outlier) = &route.outlier_detection {
+                validate_outlier_detection(outlier, &field("outlier_detection"), &mut issues);
+            }
+            if let Some(breaker) = &route.circuit_breaker {
+                validate_circuit_breaker(breaker, &field("circuit_breaker"), &mut issues);
+            }
+
+            if !is_valid_route_id(&route.id) {
+                issues.push(ConfigIssue::new(
+                    field("id"),
+                    format!(
+                        "`{}` must be non-empty and only contain letters, digits, '-', '_' or '.'",
+                        route.id
+                    ),
+                ));
+            } else if let Some(first) = seen_ids.get(route.id.as_str()) {
+                issues.push(ConfigIssue::new(
+                    field("id"),
+                    format!("`{}` is already used by routes[{}]", route.id, first),
+                ));
+            } else {
+                seen_ids.insert(&route.id, index);
+            }
+
+            let pattern = PathPattern::parse(&route.path);
+            if !route.path.starts_with('/') {
+                issues.push(ConfigIssue::new(
+                    field("path"),
+                    format!("`{}` must start with '/'", route.path),
+                ));
+            } else if let Err(message) = &pattern {
+                issues.push(ConfigIssue::new(
+                    field("path"),
+                    format!("`{}` is not a valid path template: {}", route.path, message),
+                ));
+            } else if let Ok(pattern) = &pattern {
+                let key = (
+                    route.host.as_deref().map(str::to_ascii_lowercase),
+                    pattern.canonical(),
+                );
+                let same_path = seen_paths.entry(key).or_default();
+                let duplicate = same_path
+                    .iter()
+                    .find(|&&other| self.routes[other].same_predicates(route));
+                if let Some(first) = duplicate {
+                    issues.push(ConfigIssue::new(
+                        field("path"),
+                        format!(
+                            "`{}` is already used by routes[{}] with the same predicates",
+                            route.path, first
+                        ),
+                    ));
+                }
+                same_path.push(index);
+            }
+
+            let captures: Vec<&str> = match &pattern {
+                Ok(pattern) => pattern.captures_names().collect(),
+                Err(_) => Vec::new(),
+            };
+            match &route.rewrite {
+                Some(Rewrite::ReplacePrefix { prefix }) if !prefix.starts_with('/') => {
+                    issues.push(ConfigIssue::new(
+                        format!("routes[{}].rewrite.prefix", index),
+                        format!("`{}` must start with '/'", prefix),
+                    ));
+                }
+                Some(Rewrite::Template { path }) => {
+                    let field = format!("routes[{}].rewrite.path", index);
+                    if !path.starts_with('/') {
+                        issues.push(ConfigIssue::new(
+                            &field,
+                            format!("`{}` must start with '/'", path),
+                        ));
+                    }
+                    validate_template(path, &captures, &field, &mut issues);
+                }
+                Some(Rewrite::Regex {
+                    pattern,
+                    replacement,
+                }) => {
+                    if let Err(e) = Regex::new(pattern) {
+                        issues.push(ConfigIssue::new(
+                            format!("routes[{}].rewrite.pattern", index),
+                            format!("`{}` is not a valid regex: {}", pattern, e),
+                        ));
+                    }
+                    if !replacement.starts_with('/') {
+                        issues.push(ConfigIssue::new(
+                            format!("routes[{}].rewrite.replacement", index),
+                            format!("`{}` must start with '/'", replacement),
+                        ));
+                    }
+                }
+                _ => {}
+            }
+            let header_vars: Vec<&str> = captures.iter().copied().chain(HEADER_VARS).collect();
+            validate_header_rules(
+                &route.request_headers,
+                &header_vars,
+                &format!("routes[{}].request_headers", index),
+                &mut issues,
+            );
+            validate_header_rules(
+                &route.response_headers,
+                &header_vars,
+                &format!("routes[{}].response_headers", index),
+                &mut issues,
+            );
+
+            for (i, method) in route.methods.iter().enumerate() {
+                if Method::from_bytes(method.as_bytes()).is_err() {
+                    issues.push(ConfigIssue::new(
+                        format!("routes[{}].methods[{}]", index, i),
+                        format!("`{}` is not an HTTP method", method),
+                    ));
+                }
+            }
+            for (i, header) in route.headers.iter().enumerate() {
+                let field = format!("routes[{}].headers[{}]", index, i);
+                if HeaderName::from_bytes(header.name.as_bytes()).is_err() {
+                    issues.push(ConfigIssue::new(
+                        format!("{}.name", field),
+                        format!("`{}` is not a header name", header.name),
+                    ));
+                }
+                validate_value_match(header, &field, &mut issues);
+            }
+            for (i, param) in route.query.iter().enumerate() {
+                let field = format!("routes[{}].query[{}]", index, i);
+                if param.name.is_empty() {
+                    issues.push(ConfigIssue::new(
+                        format!("{}.name", field),
+                        "must not be empty",
+                    ));
+                }
+                validate_value_match(param, &field, &mut issues);
+            }
+
+            if let Some(host) = &route.host
+                && !is_valid_host(host)
+            {
+                issues.push(ConfigIssue::new(
+                    field("host"),
+                    format!(
+                        "`{}` must be a host name such as api.example.com or *.example.com",
+                        host
+                    ),
+                ));
+            }
+
+            if route.upstreams.is_empty() {
+                if let Err(message) = validate_backend_url(&route.backend_url) {
+                    issues.push(ConfigIssue::new(field("backend_url"), message));
+                }
+            } else if !route.backend_url.is_empty() {
+                issues.push(ConfigIssue::new(
+                    field("backend_url"),
+                    "set either backend_url or upstreams, not both",
+                ));
+            }
+            for (i, upstream) in route.upstreams.iter().enumerate() {
+                let field = format!("routes[{}].upstreams[{}]", index, i);
+                if let Err(message) = validate_backend_url(&upstream.url) {
+                    issues.push(ConfigIssue::new(format!("{}.url", field), message));
+                }
+                if !(1..=MAX_WEIGHT).contains(&upstream.weight) {
+                    issues.push(ConfigIssue::new(
+                        format!("{}.weight", field),
+                        format!("must be between 1 and {}", MAX_WEIGHT),
+                    ));
+                }
+            }
+            if let Some(LoadBalancing::ConsistentHash { key }) = &route.load_balancing {
+                let valid = match key {
+                    HashKey::Header(name) => HeaderName::from_bytes(name.as_bytes()).is_ok(),
+                    HashKey::Cookie(name) => !name.is_empty(),
+                    HashKey::ClientIp => true,
+                };
+                if !valid {
+                    issues.push(ConfigIssue::new(
+                        field("load_balancing.key"),
+                        "must name a header or cookie",
+                    ));
+                }
+            }
+        }
+
+        if let Some(url) = &self.default_backend
+            && let Err(message) = validate_backend_url(url)
+        {
+            issues.push(ConfigIssue::new("default_backend", message));
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(issues))
+        }
+    }
+}
+
+/// `text` as part of a route id: runs of characters ids can't hold become a
+/// single '-', with none left at either end
+fn id_segment(text: &str) -> String {
+    text.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn is_valid_route_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Checks that `source` parses and only refers to the route's captures.
+fn validate_template(source: &str, captures: &[&str], field: &str, issues: &mut Vec<ConfigIssue>) {
+    match Template::parse(source) {
+        Ok(template) => {
+            for var in template.vars() {
+                if !captures.contains(&var) {
+                    issues.push(ConfigIssue::new(
+                        field,
+                        format!("`{{{}}}` is not captured by the route's path", var),
+                    ));
+                }
+            }
+        }
+        Err(message) => issues.push(ConfigIssue::new(
+            field,
+            format!("`{}` is not a valid template: {}", source, message),
+        )),
+    }
+}
+
+fn validate_header_rules(
+    rules: &HeaderRules,
+    vars: &[&str],
+    field: &str,
+    issues: &mut Vec<ConfigIssue>,
+) {
+    fn check_name(name: &str, field: String, issues: &mut Vec<ConfigIssue>) {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            issues.push(ConfigIssue::new(
+                field,
+                format!("`{}` is not a header name", name),
+            ));
+        }
+    }
+    for (from, to) in &rules.rename {
+        let renamed = format!("{}.rename.{}", field, from);
+        check_name(from, renamed.clone(), issues);
+        check_name(to, renamed, issues);
+    }
+    for (i, name) in rules.remove.iter().enumerate() {
+        check_name(name, format!("{}.remove[{}]", field, i), issues);
+    }
+    for (kind, values) in [("set", &rules.set), ("add", &rules.add)] {
+        for (name, value) in values {
+            let field = format!("{}.{}.{}", field, kind, name);
+            check_name(name, field.clone(), issues);
+            validate_template(value, vars, &field, issues);
+        }
+    }
+}
+
+fn validate_value_match(matcher: &ValueMatch, field: &str, issues: &mut Vec<ConfigIssue>) {
+    if matcher.value.is_some() && matcher.regex.is_some() {
+        issues.push(ConfigIssue::new(
+            field,
+            "set either `value` or `regex`, not both",
+        ));
+    }
+    if let Some(regex) = &matcher.regex
+        && let Err(e) = Regex::new(regex)
+    {
+        issues.push(ConfigIssue::new(
+            format!("{}.regex", field),
+            format!("`{}` is not a valid regex: {}", regex, e),
+        ));
+    }
+}
+
+/// Accepts a DNS name, optionally with a leading `*.` wildcard label. Ports
+/// are not part of host matching.
+fn is_valid_host(host: &str) -> bool {
+    let name = host.strip_prefix("*.").unwrap_or(host);
+    !name.is_empty()
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
         })
-        .collect()
 }
 
+fn validate_timeouts(timeouts: &TimeoutConfig, field: &str, issues: &mut Vec<ConfigIssue>) {
+    for (name, value) in timeouts.fields() {
+        if value == Some(0) {
+            issues.push(ConfigIssue::new(
+                format!("{}.{}", field, name),
+                "must be greater than zero",
+            ));
+        }
+    }
+}
+
+fn validate_health_check(check: &HealthCheckConfig, field: &str, issues: &mut Vec<ConfigIssue>) {
+    if !check.path.starts_with('/') {
+        issues.push(ConfigIssue::new(
+            format!("{}.path", field),
+            format!("`{}` must start with '/'", check.path),
+        ));
+    }
+    let positive = [
+        ("interval_ms", check.interval_ms),
+        ("timeout_ms", check.timeout_ms),
+        ("rise", u64::from(check.rise)),
+        ("fall", u64::from(check.fall)),
+    ];
+    for (name, value) in positive {
+        if value == 0 {
+            issues.push(ConfigIssue::new(
+                format!("{}.{}", field, name),
+                "must be greater than zero",
+            ));
+        }
+    }
+    for (i, status) in check.expected_status.iter().enumerate() {
+        if !(100..=599).contains(status) {
+            issues.push(ConfigIssue::new(
+                format!("{}.expected_status[{}]", field, i),
+                format!("{} is not an HTTP status code", status),
+            ));
+        }
+    }
+}
+
+fn validate_outlier_detection(
+    outlier: &OutlierDetectionConfig,
+    field: &str,
+    issues: &mut Vec<ConfigIssue>,
+) {
+    let positive = [
+        (
+            "consecutive_failures",
+            u64::from(outlier.consecutive_failures),
+        ),
+        ("base_ejection_ms", outlier.base_ejection_ms),
+    ];
+    for (name, value) in positive {
+        if value == 0 {
+            issues.push(ConfigIssue::new(
+                format!("{}.{}", field, name),
+                "must be greater than zero",
+            ));
+        }
+    }
+    if outlier.max_ejection_ms < outlier.base_ejection_ms {
+        issues.push(ConfigIssue::new(
+            format!("{}.max_ejection_ms", field),
+            "must not be less than base_ejection_ms",
+        ));
+    }
+    if outlier.max_ejection_percent > 100 {
+        issues.push(ConfigIssue::new(
+            format!("{}.max_ejection_percent", field),
+            "must be at most 100",
+        ));
+    }
+}
+
+fn validate_circuit_breaker(
+    breaker: &CircuitBreakerConfig,
+    field: &str,
+    issues: &mut Vec<ConfigIssue>,
+) {
+    if breaker.failure_rate_percent == 0 || breaker.failure_rate_percent > 100 {
+        issues.push(ConfigIssue::new(
+            format!("{}.failure_rate_percent", field),
+            "must be between 1 and 100",
+        ));
+    }
+    let positive = [
+        ("window_ms", breaker.window_ms),
+        ("open_ms", breaker.open_ms),
+        ("half_open_requests", u64::from(breaker.half_open_requests)),
+    ];
+    for (name, value) in positive {
+        if value == 0 {
+            issues.push(ConfigIssue::new(
+                format!("{}.{}", field, name),
+                "must be greater than zero",
+            ));
+        }
+    }
+}
+
+fn validate_retry(retry: &RetryConfig, field: &str, issues: &mut Vec<ConfigIssue>) {
+    if retry.attempts == 0 {
+        issues.push(ConfigIssue::new(
+            format!("{}.attempts", field),
+            "must be greater than zero",
+        ));
+    }
+    for (i, method) in retry.methods.iter().enumerate() {
+        if Method::from_bytes(method.as_bytes()).is_err() {
+            issues.push(ConfigIssue::new(
+                format!("{}.methods[{}]", field, i),
+                format!("`{}` is not an HTTP method", method),
+            ));
+        }
+    }
+    for (i, status) in retry.on_status.iter().enumerate() {
+        if !(100..=599).contains(status) {
+            issues.push(ConfigIssue::new(
+                format!("{}.on_status[{}]", field, i),
+                format!("{} is not an HTTP status code", status),
+            ));
+        }
+    }
+    if retry.backoff_max_ms < retry.backoff_base_ms {
+        issues.push(ConfigIssue::new(
+            format!("{}.backoff_max_ms", field),
+            "must not be less than backoff_base_ms",
+        ));
+    }
+}
+
+fn validate_backend_url(url: &str) -> Result<(), String> {
+    let parsed = Url::parse(url).map_err(|e| format!("`{}` is not a valid URL: {}", url, e))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err(format!("`{}` must use the http or https scheme", url));
+    }
+    if parsed.host_str().is_none() {
+        return Err(format!("`{}` has no host", url));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issues(json: &str) -> Vec<String> {
+        let config = match parse_config(json) {
+            Ok(config) => config,
+            Err(issue) => return vec![issue.to_string()],
+        };
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(ConfigError::Invalid(issues)) => issues.iter().map(|i| i.to_string()).collect(),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_valid_config_uses_defaults() {
+        let config =
+            parse_config(r#"{"routes": [{"path": "/bin", "backend_url": "http://httpbin.org"}]}"#)
+                .unwrap();
+
+        assert!(config.validate().is_ok());
+        assert_eq!(config.server.bind, "127.0.0.1:3000");
+        assert!(config.rate_limit.enabled);
+        assert_eq!(config.routes[0].backend_url, "http://httpbin.org");
+    }
+
+    #[test]
+    fn test_missing_routes() {
+        let result = issues(r#"{"server": {"bind": "127.0.0.1:3000"}}"#);
+        assert_eq!(result.len(), 1);
+        assert!(result[0].contains("missing field `routes`"));
+    }
+
+    #[test]
+    fn test_misspelled_field_names_route() {
+        let result = issues(
+            r#"{"routes": [
+                {"path": "/a", "backend_url": "http://a.example.com"},
+                {"path": "/b", "backend_ur": "http://b.example.com"}
+            ]}"#,
+        );
+        assert_eq!(result.len(), 1);
+        assert!(result[0].starts_with("routes[1]"));
+        assert!(result[0].contains("backend_ur"));
+    }
+
+    #[test]
+    fn test_invalid_routes_are_all_reported() {
+        let result = issues(
+            r#"{"routes": [
+                {"path": "/a", "backend_url": "http://a.example.com"},
+                {"path": "b", "backend_url": "not a url"},
+                {"path": "/a", "backend_url": "ftp://a.example.com"}
+            ]}"#,
+        );
+        assert_eq!(result.len(), 4);
+        assert!(result[0].starts_with("routes[1].path"));
+        assert!(result[1].starts_with("routes[1].backend_url"));
+        assert!(result[2].starts_with("routes[2].path"));
+        assert!(result[2].contains("routes[0]"));
+        assert!(result[3].starts_with("routes[2].backend_url"));
+    }
+
+    #[test]
+    fn test_body_limits_fall_back_to_global() {
+        let config = parse_config(
+            r#"{"limits": {"max_request_body": 1024},
+                "routes": [
+                {"path": "/a", "backend_url": "http://a.internal"},
+                {"path": "/b", "backend_url": "http://b.internal",
+                 "max_request_body": 10, "max_response_body": 20}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.max_request_body(&config.routes[0]), Some(1024));
+        assert_eq!(config.max_response_body(&config.routes[0]), None);
+        assert_eq!(config.max_request_body(&config.routes[1]), Some(10));
+        assert_eq!(config.max_response_body(&config.routes[1]), Some(20));
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let config = parse_config(
+            r#"{"timeouts": {"total_ms": 30000},
+                "routes": [
+                {"path": "/a", "backend_url": "http://a.internal"},
+                {"path": "/b", "backend_url": "http://b.internal",
+                 "timeouts": {"connect_ms": 500, "total_ms": 0}}
+            ]}"#,
+        )
+        .unwrap();
+
+        let a = config.timeouts(&config.routes[0]);
+        assert_eq!(a.connect, Duration::from_secs(10));
+        assert_eq!(a.total, Some(Duration::from_secs(30)));
+        assert_eq!(a.read_idle, Duration::from_secs(60));
+        assert_eq!(a.stream_idle, Duration::from_secs(300));
+        assert_eq!(a.websocket_idle, Duration::from_secs(300));
+        assert_eq!(a.websocket_lifetime, None);
+        assert_eq!(
+            config.timeouts(&config.routes[1]).connect,
+            Duration::from_millis(500)
+        );
+
+        let result = issues(&serde_json::to_string(&config).unwrap());
+        assert_eq!(result.len(), 1);
+        assert!(result[0].starts_with("routes[1].timeouts.total_ms"));
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let config = parse_config(
+            r#"{"routes": [
+                {"path": "/a", "backend_url": "http://a.internal", "retry": {}},
+                {"path": "/b", "backend_url": "http://b.internal",
+                 "retry": {"attempts": 0, "methods": ["POST"], "on_status": [429, 700],
+                           "backoff_base_ms": 500, "backoff_max_ms": 100}}
+            ]}"#,
+        )
+        .unwrap();
+
+        let retry = config.routes[0].retry.as_ref().unwrap();
+        assert_eq!(retry.attempts, 3);
+        assert!(retry.allows_method(&Method::GET));
+        assert!(retry.allows_method(&Method::PUT));
+        assert!(!retry.allows_method(&Method::POST));
+        assert!(
+            config.routes[1]
+                .retry
+                .as_ref()
+                .unwrap()
+                .allows_method(&Method::POST)
+        );
+
+        let result = issues(&serde_json::to_string(&config).unwrap());
+        assert_eq!(result.len(), 3);
+        assert!(result[0].starts_with("routes[1].retry.attempts"));
+        assert!(result[1].starts_with("routes[1].retry.on_status[1]"));
+        assert!(result[2].starts_with("routes[1].retry.backoff_max_ms"));
+    }
+
+    #[test]
+    fn test_upstreams() {
+        let result = issues(
+            r#"{"routes": [
+                {"path": "/a", "upstreams": [{"url": "http://a1.internal"}, {"url": "http://a2.internal", "weight": 3}],
+                 "load_balancing": {"strategy": "consistent_hash", "key": {"header": "X-User"}}},
+                {"path": "/b", "backend_url": "http://b.internal", "upstreams": [{"url": "http://b1.internal"}]},
+                {"path": "/c", "upstreams": [{"url": "c.internal", "weight": 0}],
+                 "load_balancing": {"strategy": "least_outstanding"}},
+                {"path": "/d"},
+                {"path": "/e", "upstreams": [{"url": "http://e.internal", "weight": 4000000000}]}
+            ]}"#,
+        );
+        assert_eq!(result.len(), 5);
+        assert!(result[0].starts_with("routes[1].backend_url: set either"));
+        assert!(result[1].starts_with("routes[2].upstreams[0].url"));
+        assert!(result[2].starts_with("routes[2].upstreams[0].weight"));
+        assert!(result[3].starts_with("routes[3].backend_url"));
+        assert!(
+            result[4].starts_with("routes[4].upstreams[0].weight: must be between 1 and 10000")
+        );
+    }
+
+    #[test]
+    fn test_health_check() {
+        let config = parse_config(
+            r#"{"routes": [
+                {"path": "/a", "backend_url": "http://a.internal", "health_check": {}},
+                {"path": "/b", "backend_url": "http://b.internal",
+                 "health_check": {"path": "status", "fall": 0, "expected_status": [204, 1000]}}
+            ]}"#,
+        )
+        .unwrap();
+
+        let check = config.routes[0].health_check.as_ref().unwrap();
+        assert_eq!(check.path, "/health");
+        assert_eq!(check.expected_status, vec![200]);
+
+        let result = issues(&serde_json::to_string(&config).unwrap());
+        assert_eq!(result.len(), 3);
+        assert!(result[0].starts_with("routes[1].health_check.path"));
+        assert!(result[1].starts_with("routes[1].health_check.fall"));
+        assert!(result[2].starts_with("routes[1].health_check.expected_status[1]"));
+    }
+
+    #[test]
+    fn test_outlier_detection() {
+        let result = issues(
+            r#"{"routes": [
+                {"path": "/a", "backend_url": "http://a.internal", "outlier_detection": {}},
+                {"path": "/b", "backend_url": "http://b.internal",
+                 "outlier_detection": {"consecutive_failures": 0, "max_ejection_ms": 1000,
+                                       "max_ejection_percent": 150}}
+            ]}"#,
+        );
+        assert_eq!(result.len(), 3);
+        assert!(result[0].starts_with("routes[1].outlier_detection.consecutive_failures"));
+        assert!(result[1].starts_with("routes[1].outlier_detection.max_ejection_ms"));
+        assert!(result[2].starts_with("routes[1].outlier_detection.max_ejection_percent"));
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let result = issues(
+            r#"{"routes": [
+                {"path": "/a", "backend_url": "http://a.internal", "circuit_breaker": {}},
+                {"path": "/b", "backend_url": "http://b.internal",
+                 "circuit_breaker": {"failure_rate_percent": 101, "open_ms": 0}}
+            ]}"#,
+        );
+        assert_eq!(result.len(), 2);
+        assert!(result[0].starts_with("routes[1].circuit_breaker.failure_rate_percent"));
+        assert!(result[1].starts_with("routes[1].circuit_breaker.open_ms"));
+    }
+
+    #[test]
+    fn test_invalid_trusted_proxies() {
+        let result = issues(
+            r#"{"forwarded": {"trusted_proxies": ["10.0.0.0/8", "::1", "10.0.0.0/33", "proxy"]},
+                "routes": []}"#,
+        );
+        assert_eq!(result.len(), 2);
+        assert!(result[0].starts_with("forwarded.trusted_proxies[2]"));
+        assert!(result[1].starts_with("forwarded.trusted_proxies[3]"));
+    }
+
+    #[test]
+    fn test_invalid_default_backend() {
+        let result = issues(r#"{"default_backend": "ftp://files.internal", "routes": []}"#);
+        assert_eq!(result.len(), 1);
+        assert!(result[0].starts_with("default_backend"));
+    }
+
+    #[test]
+    fn test_same_path_on_different_hosts() {
+        let result = issues(
+            r#"{"routes": [
+                {"host": "api.example.com", "path": "/", "backend_url": "http://api.internal"},
+                {"host": "*.example.com", "path": "/", "backend_url": "http://tenants.internal"},
+                {"host": "API.example.com", "path": "/", "backend_url": "http://api2.internal"},
+                {"host": "api.*.com", "path": "/x", "backend_url": "http://api3.internal"}
+            ]}"#,
+        );
+        assert_eq!(result.len(), 2);
+        assert!(result[0].starts_with("routes[2].path"));
+        assert!(result[1].starts_with("routes[3].host"));
+    }
+
+    #[test]
+    fn test_predicates() {
+        let result = issues(
+            r#"{"routes": [
+                {"id": "list", "path": "/orders", "methods": ["GET"], "backend_url": "http://a.internal"},
+                {"id": "create", "path": "/orders", "methods": ["post"], "backend_url": "http://b.internal"},
+                {"id": "canary", "path": "/orders", "backend_url": "http://c.internal",
+                 "headers": [{"name": "x-canary", "value": "true"}]},
+                {"id": "again", "path": "/orders", "methods": ["POST"], "backend_url": "http://d.internal"},
+                {"id": "bad", "path": "/bad", "methods": ["GET POST"], "backend_url": "http://e.internal",
+                 "headers": [{"name": "x canary", "value": "a", "regex": "("}],
+                 "query": [{"name": "v", "regex": "^[0-9]+$"}]}
+            ]}"#,
+        );
+        assert_eq!(result.len(), 5);
+        assert!(result[0].starts_with("routes[3].path"));
+        assert!(result[0].contains("routes[1]"));
+        assert!(result[1].starts_with("routes[4].methods[0]"));
+        assert!(result[2].starts_with("routes[4].headers[0].name"));
+        assert!(result[3].starts_with("routes[4].headers[0]: set either"));
+        assert!(result[4].starts_with("routes[4].headers[0].regex"));
+    }
+
+    #[test]
+    fn test_path_templates() {
+        let result = issues(
+            r#"{"routes": [
+                {"path": "/users/{id}/orders/{order_id}", "backend_url": "http://a.internal",
+                 "rewrite": {"mode": "template", "path": "/v2/customers/{id}/orders/{order_id}"},
+                 "request_headers": {"set": {"X-Customer-Id": "{id}"}}},
+                {"path": "/users/{user_id}/orders/{oid}", "backend_url": "http://b.internal"},
+                {"path": "/files/{*rest}/x", "backend_url": "http://c.internal"},
+                {"path": "/docs/{*rest}", "backend_url": "http://d.internal",
+                 "rewrite": {"mode": "template", "path": "/{rest}/{page}"}}
+            ]}"#,
+        );
+        assert_eq!(result.len(), 3);
+        assert!(result[0].starts_with("routes[1].path"));
+        assert!(result[0].contains("routes[0]"));
+        assert!(result[1].starts_with("routes[2].path"));
+        assert!(result[2].starts_with("routes[3].rewrite.path: `{page}`"));
+    }
+
+    #[test]
+    fn test_header_rules() {
+        let result = issues(
+            r#"{"routes": [
+                {"path": "/users/{id}", "backend_url": "http://a.internal",
+                 "request_headers": {"rename": {"X-Token": "Bad Name"},
+                                     "set": {"X-User": "{id}", "X-Trace": "{request_id}"}},
+                 "response_headers": {"remove": ["X-Powered-By", "bad:name"],
+                                      "add": {"X-Served-By": "{route} for {client_ip}",
+                                              "X-Missing": "{order_id}"}}}
+            ]}"#,
+        );
+        assert_eq!(result.len(), 3);
+        assert!(result[0].starts_with("routes[0].request_headers.rename.X-Token: `Bad Name`"));
+        assert!(result[1].starts_with("routes[0].response_headers.remove[1]"));
+        assert!(result[2].starts_with("routes[0].response_headers.add.X-Missing: `{order_id}`"));
+    }
+
+    #[test]
+    fn test_rewrite_modes() {
+        let result = issues(
+            r#"{"routes": [
+                {"path": "/a", "backend_url": "http://a.internal", "rewrite": {"mode": "preserve"}},
+                {"path": "/b", "backend_url": "http://b.internal",
+                 "rewrite": {"mode": "replace_prefix", "prefix": "v2"}},
+                {"path": "/c", "backend_url": "http://c.internal",
+                 "rewrite": {"mode": "regex", "pattern": "^/c/(", "replacement": "/$1"}},
+                {"path": "/d", "backend_url": "http://d.internal",
+                 "rewrite": {"mode": "regex", "pattern": "^/d/(.*)$", "replacement": "/$1"}},
+                {"path": "/e", "backend_url": "http://e.internal",
+                 "rewrite": {"mode": "regex", "pattern": "^/e/(.*)$", "replacement": "$1"}}
+            ]}"#,
+        );
+        assert_eq!(result.len(), 3);
+        assert!(result[0].starts_with("routes[1].rewrite.prefix"));
+        assert!(result[1].starts_with("routes[2].rewrite.pattern"));
+        assert!(result[2].starts_with("routes[4].rewrite.replacement"));
+    }
+
+    #[test]
+    fn test_route_ids_derived_from_path() {
+        let config = parse_config(
+            r#"{"routes": [
+                {"path": "/", "backend_url": "http://root.example.com"},
+                {"path": "/v1/users", "backend_url": "http://users.example.com"},
+                {"id": "auth", "path": "/login", "backend_url": "http://auth.example.com"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let ids: Vec<&str> = config.routes.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["root", "v1-users", "auth"]);
+    }
+
+    #[test]
+    fn test_route_ids_for_routes_told_apart_by_predicates() {
+        let json = r#"{"routes": [
+            {"path": "/orders", "methods": ["GET"], "backend_url": "http://read.internal"},
+            {"path": "/orders", "methods": ["POST"], "backend_url": "http://write.internal"},
+            {"path": "/orders", "headers": [{"name": "x-beta", "value": "1"}],
+             "backend_url": "http://beta.internal"},
+            {"path": "/orders", "backend_url": "http://orders.internal"}
+        ]}"#;
+        let config = parse_config(json).unwrap();
+
+        let ids: Vec<&str> = config.routes.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["orders-get", "orders-post", "orders", "orders-2"]);
+        assert!(issues(json).is_empty());
+    }
+
+    #[test]
+    fn test_generated_route_ids_are_valid_and_unique() {
+        let json = r#"{"routes": [
+            {"path": "/a/b", "backend_url": "http://a.internal"},
+            {"path": "/a-b", "backend_url": "http://a.internal"},
+            {"path": "/~user/%20/v1.0", "backend_url": "http://a.internal"},
+            {"path": "/taken", "backend_url": "http://a.internal"},
+            {"id": "taken", "path": "/other", "backend_url": "http://a.internal"},
+            {"path": "/", "host": "*.example.com", "backend_url": "http://a.internal"}
+        ]}"#;
+        let config = parse_config(json).unwrap();
+
+        let ids: Vec<&str> = config.routes.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec![
+                "a-b",
+                "a-b-2",
+                "user-20-v1.0",
+                "taken-2",
+                "taken",
+                "wildcard.example.com-root"
+            ]
+        );
+        assert!(issues(json).is_empty());
+    }
+}
@@ -1,34 +1,224 @@
-use axum::response::{IntoResponse, Response};
-use http::StatusCode;
+use std::fmt;
+
+use axum::{
+    Json,
+    response::{IntoResponse, Response},
+};
+use http::{StatusCode, header::WWW_AUTHENTICATE};
+use serde_json::json;
 use tracing::error;
 
+use crate::grpc;
+
 #[derive(Debug)]
 pub enum ProxyError {
+    /// No route matches the request and there's no `default_backend`
+    NoRoute(String),
+    /// The request body is over the route's `max_request_body`
+    PayloadTooLarge(u64),
+    /// The backend's response body is over the route's `max_response_body`
+    ResponseTooLarge(u64),
+    /// The backend didn't connect, respond or finish in time
+    Timeout(String),
+    /// Every upstream of the route is out of rotation
+    NoHealthyUpstream(String),
+    /// The circuit breaker is open for every available upstream of the route
+    CircuitOpen(String),
+    /// A WebSocket upgrade request that can't be upgraded
+    UpgradeError(String),
+    /// The request already passed through this gateway
+    LoopDetected,
     BackendError(String),
     BodyError(String),
     ResponseError(String),
 }
 
+impl ProxyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::NoRoute(_) => StatusCode::NOT_FOUND,
+            ProxyError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ProxyError::ResponseTooLarge(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::NoHealthyUpstream(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::UpgradeError(_) => StatusCode::BAD_REQUEST,
+            ProxyError::LoopDetected => StatusCode::LOOP_DETECTED,
+            ProxyError::BackendError(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::BodyError(_) => StatusCode::BAD_REQUEST,
+            ProxyError::ResponseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The gRPC status code reported to gRPC clients instead of `status`
+    pub fn grpc_status(&self) -> u32 {
+        match self {
+            ProxyError::NoRoute(_) => grpc::UNIMPLEMENTED,
+            ProxyError::PayloadTooLarge(_) => grpc::RESOURCE_EXHAUSTED,
+            ProxyError::ResponseTooLarge(_) => grpc::RESOURCE_EXHAUSTED,
+            ProxyError::Timeout(_) => grpc::DEADLINE_EXCEEDED,
+            ProxyError::NoHealthyUpstream(_) => grpc::UNAVAILABLE,
+            ProxyError::CircuitOpen(_) => grpc::UNAVAILABLE,
+            ProxyError::UpgradeError(_) => grpc::INTERNAL,
+            ProxyError::LoopDetected => grpc::INTERNAL,
+            ProxyError::BackendError(_) => grpc::UNAVAILABLE,
+            ProxyError::BodyError(_) => grpc::INTERNAL,
+            ProxyError::ResponseError(_) => grpc::INTERNAL,
+        }
+    }
+
+    /// Describes the error for the client
+    pub fn message(&self) -> String {
+        match self {
+            ProxyError::NoRoute(path) => format!("No route matches {}", path),
+            ProxyError::PayloadTooLarge(limit) => {
+                format!("Request body is larger than {} bytes", limit)
+            }
+            ProxyError::ResponseTooLarge(limit) => {
+                format!("Backend response is larger than {} bytes", limit)
+            }
+            ProxyError::Timeout(msg) => format!("Backend timeout: {}", msg),
+            ProxyError::NoHealthyUpstream(route) => {
+                format!("No healthy upstream for route `{}`", route)
+            }
+            ProxyError::CircuitOpen(route) => format!("Circuit breaker open for route `{}`", route),
+            ProxyError::UpgradeError(msg) => format!("Invalid upgrade request: {}", msg),
+            ProxyError::LoopDetected => "Request already passed through this gateway".to_string(),
+            ProxyError::BackendError(msg) => format!("Backend error: {}", msg),
+            ProxyError::BodyError(msg) => format!("Body error: {}", msg),
+            ProxyError::ResponseError(msg) => format!("Response error: {}", msg),
+        }
+    }
+
+    /// Logs the error unless it is the client's fault
+    pub fn log(&self) {
+        match self {
+            ProxyError::NoRoute(_)
+            | ProxyError::PayloadTooLarge(_)
+            | ProxyError::UpgradeError(_) => {}
+            ProxyError::LoopDetected => error!("Request looped back to the gateway"),
+            _ => error!("{}", self.message()),
+        }
+    }
+}
+
 impl IntoResponse for ProxyError {
     fn into_response(self) -> Response {
-        let (status, message) = match self {
-            ProxyError::BackendError(msg) => {
-                error!("Backend error: {}", msg);
-                (StatusCode::BAD_GATEWAY, format!("Backend error: {}", msg))
+        (self.status(), self.message()).into_response()
+    }
+}
+
+/// A single problem found in the config, e.g. `routes[1].path`
+#[derive(Debug, Clone)]
+pub struct ConfigIssue {
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: String, message: String },
+    Write { path: String, message: String },
+    Parse { path: String, issue: ConfigIssue },
+    Invalid(Vec<ConfigIssue>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, message } => {
+                write!(f, "couldn't read config file {}: {}", path, message)
+            }
+            ConfigError::Write { path, message } => {
+                write!(f, "couldn't write config file {}: {}", path, message)
+            }
+            ConfigError::Parse { path, issue } => {
+                write!(f, "couldn't parse config file {}: {}", path, issue)
             }
-            ProxyError::BodyError(msg) => {
-                error!("Body error: {}", msg);
-                (StatusCode::BAD_REQUEST, format!("Body error: {}", msg))
+            ConfigError::Invalid(issues) => {
+                write!(f, "invalid configuration:")?;
+                for issue in issues {
+                    write!(f, "\n  {}", issue)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Errors returned by the route admin API
+#[derive(Debug)]
+pub enum AdminError {
+    /// No `server.admin_token` is configured, so routes can't be changed
+    Disabled,
+    /// The request didn't carry the admin token
+    Unauthorized,
+    RouteNotFound(String),
+    RouteExists(String),
+    Invalid(ConfigError),
+    Persist(ConfigError),
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let (status, body) = match self {
+            AdminError::Disabled => (
+                StatusCode::FORBIDDEN,
+                json!({ "error": "route changes are disabled, set `server.admin_token` to enable them" }),
+            ),
+            AdminError::Unauthorized => {
+                return (
+                    StatusCode::UNAUTHORIZED,
+                    [(WWW_AUTHENTICATE, "Bearer")],
+                    Json(json!({ "error": "missing or invalid admin token" })),
+                )
+                    .into_response();
             }
-            ProxyError::ResponseError(msg) => {
-                error!("Response error: {}", msg);
+            AdminError::RouteNotFound(id) => (
+                StatusCode::NOT_FOUND,
+                json!({ "error": format!("route `{}` not found", id) }),
+            ),
+            AdminError::RouteExists(id) => (
+                StatusCode::CONFLICT,
+                json!({ "error": format!("route `{}` already exists", id) }),
+            ),
+            AdminError::Invalid(ConfigError::Invalid(issues)) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({
+                    "error": "invalid route",
+                    "issues": issues.iter().map(|i| i.to_string()).collect::<Vec<_>>(),
+                }),
+            ),
+            AdminError::Invalid(e) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({ "error": e.to_string() }),
+            ),
+            AdminError::Persist(e) => {
+                error!("Failed to persist route change: {}", e);
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Response error: {}", msg),
+                    json!({ "error": e.to_string() }),
                 )
             }
         };
 
-        (status, message).into_response()
+        (status, Json(body)).into_response()
     }
 }
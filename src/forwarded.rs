@@ -0,0 +1,169 @@
+use std::net::IpAddr;
+
+use http::{HeaderMap, HeaderName, HeaderValue, header::FORWARDED};
+use ipnet::IpNet;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// The gateway only listens for plain HTTP
+const PROTO: &str = "http";
+
+/// Parses a `trusted_proxies` entry, either a CIDR block or a single address
+pub fn parse_proxy(entry: &str) -> Option<IpNet> {
+    entry
+        .parse()
+        .ok()
+        .or_else(|| entry.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// Adds the forwarding headers for a request from `peer` for `host`.
+/// Headers sent by a trusted proxy are appended to or kept; anyone else's
+/// are dropped first, since the client could have made them up.
+pub fn add_forwarding_headers(
+    headers: &mut HeaderMap,
+    host: Option<&str>,
+    peer: IpAddr,
+    trusted_proxies: &[IpNet],
+) {
+    let trusted = |addr: IpAddr| trusted_proxies.iter().any(|net| net.contains(&addr));
+    if !trusted(peer) {
+        for name in [
+            FORWARDED,
+            X_FORWARDED_FOR,
+            X_FORWARDED_PROTO,
+            X_FORWARDED_HOST,
+            X_REAL_IP,
+        ] {
+            headers.remove(name);
+        }
+    }
+
+    // The address nearest the gateway that isn't one of our proxies
+    let chain = joined(headers, &X_FORWARDED_FOR);
+    let mut client = peer;
+    if let Some(chain) = &chain {
+        for entry in chain.rsplit(',') {
+            if !trusted(client) {
+                break;
+            }
+            match entry.trim().parse() {
+                Ok(addr) => client = addr,
+                Err(_) => break,
+            }
+        }
+    }
+
+    let proto = joined(headers, &X_FORWARDED_PROTO).unwrap_or_else(|| PROTO.to_string());
+    let host = joined(headers, &X_FORWARDED_HOST).or_else(|| host.map(String::from));
+
+    let mut node = format!("for={}", forwarded_node(peer));
+    if let Some(host) = &host {
+        node.push_str(&format!(";host=\"{}\"", host.replace(['"', '\\'], "")));
+    }
+    node.push_str(&format!(";proto={}", PROTO));
+    let set = [
+        (FORWARDED, append(joined(headers, &FORWARDED), &node)),
+        (X_FORWARDED_FOR, append(chain, &peer.to_string())),
+        (X_FORWARDED_PROTO, proto),
+        (X_REAL_IP, client.to_string()),
+    ];
+    for (name, value) in set {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+    if let Some(host) = host
+        && let Ok(value) = HeaderValue::from_str(&host)
+    {
+        headers.insert(X_FORWARDED_HOST, value);
+    }
+}
+
+/// All values of a header as one comma-separated list
+fn joined(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+fn append(list: Option<String>, value: &str) -> String {
+    match list {
+        Some(list) => format!("{}, {}", list, value),
+        None => value.to_string(),
+    }
+}
+
+/// An address as a `Forwarded` node, which quotes IPv6 in brackets
+fn forwarded_node(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => addr.to_string(),
+        IpAddr::V6(addr) => format!("\"[{}]\"", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incoming() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED, HeaderValue::from_static("for=203.0.113.9"));
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("203.0.113.9"));
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+        headers.insert(X_REAL_IP, HeaderValue::from_static("203.0.113.9"));
+        headers
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &HeaderName) -> &'a str {
+        headers.get(name).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn test_untrusted_headers_are_replaced() {
+        let mut headers = incoming();
+        let peer = "198.51.100.7".parse().unwrap();
+        add_forwarding_headers(&mut headers, Some("api.example.com"), peer, &[]);
+
+        assert_eq!(
+            header(&headers, &FORWARDED),
+            "for=198.51.100.7;host=\"api.example.com\";proto=http"
+        );
+        assert_eq!(header(&headers, &X_FORWARDED_FOR), "198.51.100.7");
+        assert_eq!(header(&headers, &X_FORWARDED_PROTO), "http");
+        assert_eq!(header(&headers, &X_FORWARDED_HOST), "api.example.com");
+        assert_eq!(header(&headers, &X_REAL_IP), "198.51.100.7");
+    }
+
+    #[test]
+    fn test_trusted_proxy_chain_is_appended() {
+        let mut headers = incoming();
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.3"));
+        let trusted = [
+            parse_proxy("10.0.0.0/8").unwrap(),
+            parse_proxy("::1").unwrap(),
+        ];
+        add_forwarding_headers(
+            &mut headers,
+            Some("api.example.com"),
+            "::1".parse().unwrap(),
+            &trusted,
+        );
+
+        assert_eq!(
+            header(&headers, &FORWARDED),
+            "for=203.0.113.9, for=\"[::1]\";host=\"api.example.com\";proto=http"
+        );
+        assert_eq!(
+            header(&headers, &X_FORWARDED_FOR),
+            "203.0.113.9, 10.0.0.3, ::1"
+        );
+        assert_eq!(header(&headers, &X_FORWARDED_PROTO), "https");
+        assert_eq!(header(&headers, &X_REAL_IP), "203.0.113.9");
+    }
+}
@@ -0,0 +1,76 @@
+use axum::{body::Body, response::Response};
+use http::{HeaderMap, HeaderName, HeaderValue, header::CONTENT_TYPE};
+
+use crate::error::ProxyError;
+
+// Status codes the gateway reports itself, from
+// https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+pub const DEADLINE_EXCEEDED: u32 = 4;
+pub const RESOURCE_EXHAUSTED: u32 = 8;
+pub const UNIMPLEMENTED: u32 = 12;
+pub const INTERNAL: u32 = 13;
+pub const UNAVAILABLE: u32 = 14;
+
+const GRPC_STATUS: HeaderName = HeaderName::from_static("grpc-status");
+const GRPC_MESSAGE: HeaderName = HeaderName::from_static("grpc-message");
+
+/// Whether the message is gRPC, going by its `application/grpc[+proto]`
+/// content type
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.get(..16))
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("application/grpc"))
+}
+
+/// The `grpc-status` in response headers or trailers
+pub fn status(headers: &HeaderMap) -> Option<u32> {
+    headers.get(GRPC_STATUS)?.to_str().ok()?.parse().ok()
+}
+
+/// A trailers-only gRPC response for an error, since gRPC clients expect a
+/// 200 with the outcome in `grpc-status` rather than an HTTP error status
+pub fn error_response(e: &ProxyError) -> Response {
+    let mut response = Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    headers.insert(GRPC_STATUS, HeaderValue::from(e.grpc_status()));
+    if let Ok(message) = HeaderValue::from_str(&encode_message(&e.message())) {
+        headers.insert(GRPC_MESSAGE, message);
+    }
+    response
+}
+
+/// Percent-encodes `grpc-message`, which may only hold printable ASCII
+/// other than `%`
+fn encode_message(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (b' '..=b'~').contains(&byte) && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    #[test]
+    fn test_error_response() {
+        let response = error_response(&ProxyError::NoHealthyUpstream("users".to_string()));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(is_grpc(response.headers()));
+        assert_eq!(status(response.headers()), Some(UNAVAILABLE));
+        assert_eq!(
+            response.headers()[GRPC_MESSAGE],
+            "No healthy upstream for route `users`"
+        );
+        assert_eq!(encode_message("50% ünits"), "50%25 %C3%BCnits");
+    }
+}
@@ -1,12 +1,19 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
 use axum::{
-    Json,
-    extract::{Query, State, WebSocketUpgrade, ws::Message},
+    Json, Router,
+    extract::{Path, Query, Request, State, WebSocketUpgrade, ws::Message},
+    middleware::{self, Next},
+    response::Response,
+    routing::post,
 };
+use http::{StatusCode, header::AUTHORIZATION};
 
 use crate::{
-    models::{LogQuery, RequestLog, SummaryMetrics},
+    config::Route,
+    error::AdminError,
+    models::{LogQuery, ReloadTrigger, RequestLog, SummaryMetrics, UpstreamStatus},
+    reload,
     state::AppState,
 };
 
@@ -15,6 +22,33 @@ pub async fn get_metrics(State(state): State<Arc<AppState>>) -> Json<SummaryMetr
     Json(state.metrics_store.get_metrics())
 }
 
+// GET /api/upstreams - Returns the upstreams of the current routes and
+// their health
+pub async fn get_upstreams(State(state): State<Arc<AppState>>) -> Json<Vec<UpstreamStatus>> {
+    let routes = state.routes.load();
+    let mut upstreams: BTreeMap<String, UpstreamStatus> = BTreeMap::new();
+    for route in routes.routes() {
+        for target in route.targets() {
+            let status = upstreams.entry(target.url.clone()).or_insert_with(|| {
+                let upstream = state.upstreams.get(&target.url);
+                UpstreamStatus {
+                    url: target.url,
+                    routes: Vec::new(),
+                    health_checked: false,
+                    healthy: upstream.is_healthy(),
+                    last_checked: upstream.last_checked(),
+                    ejected_until: upstream.ejected_until(),
+                    circuit: upstream.breaker().state(),
+                    outstanding: upstream.outstanding(),
+                }
+            });
+            status.routes.push(route.id.clone());
+            status.health_checked |= route.health_check.is_some();
+        }
+    }
+    Json(upstreams.into_values().collect())
+}
+
 // GET /api/logs?limit=50 - Returns recent logs
 pub async fn get_logs(
     State(state): State<Arc<AppState>>,
@@ -27,11 +61,113 @@ pub async fn get_logs(
 
 // GET /api/routes - Returns current routing configuration
 #[axum::debug_handler]
-pub async fn get_routes(State(state): State<Arc<AppState>>) -> Json<Vec<crate::state::Route>> {
-    let routes = state.routes.clone();
+pub async fn get_routes(State(state): State<Arc<AppState>>) -> Json<Vec<crate::config::Route>> {
+    let routes = state.routes.load().routes().to_vec();
     Json(routes)
 }
 
+/// The endpoints that change routes, which require the admin token
+pub fn route_admin(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route(
+            "/api/routes/{id}",
+            post(create_route).put(update_route).delete(delete_route),
+        )
+        .route_layer(middleware::from_fn_with_state(state, require_admin_token))
+}
+
+/// Only lets a request through if it carries `server.admin_token` as a
+/// bearer token. Anyone who can change routes can send traffic anywhere,
+/// so there is no way in without a token.
+async fn require_admin_token(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AdminError> {
+    let authorized = {
+        let routes = state.routes.load();
+        let Some(token) = &routes.config().server.admin_token else {
+            return Err(AdminError::Disabled);
+        };
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes()))
+    };
+    if !authorized {
+        return Err(AdminError::Unauthorized);
+    }
+    Ok(next.run(req).await)
+}
+
+/// Compares without returning early, so the time taken doesn't give away
+/// how much of the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// POST /api/routes/{id} - Registers a new route
+pub async fn create_route(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(mut route): Json<Route>,
+) -> Result<(StatusCode, Json<Route>), AdminError> {
+    route.id = id;
+    let created = route.clone();
+    let route_count = state.update_config(|config| {
+        if config.routes.iter().any(|r| r.id == route.id) {
+            return Err(AdminError::RouteExists(route.id.clone()));
+        }
+        config.routes.push(route);
+        Ok(config.routes.len())
+    })?;
+
+    reload::report(&state, ReloadTrigger::AdminApi, Ok(route_count));
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+// PUT /api/routes/{id} - Replaces an existing route
+pub async fn update_route(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(mut route): Json<Route>,
+) -> Result<Json<Route>, AdminError> {
+    route.id = id;
+    let updated = route.clone();
+    let route_count = state.update_config(|config| {
+        let existing = config
+            .routes
+            .iter_mut()
+            .find(|r| r.id == route.id)
+            .ok_or_else(|| AdminError::RouteNotFound(route.id.clone()))?;
+        *existing = route;
+        Ok(config.routes.len())
+    })?;
+
+    reload::report(&state, ReloadTrigger::AdminApi, Ok(route_count));
+    Ok(Json(updated))
+}
+
+// DELETE /api/routes/{id} - Removes a route
+pub async fn delete_route(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    let route_count = state.update_config(|config| {
+        let index = config
+            .routes
+            .iter()
+            .position(|r| r.id == id)
+            .ok_or_else(|| AdminError::RouteNotFound(id.clone()))?;
+        config.routes.remove(index);
+        Ok(config.routes.len())
+    })?;
+
+    reload::report(&state, ReloadTrigger::AdminApi, Ok(route_count));
+    Ok(StatusCode::NO_CONTENT)
+}
+
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
@@ -45,25 +181,127 @@ pub async fn websocket_handler(
                 msg = socket.recv() => {
                     match msg {
                         Some(Ok(Message::Close(_))) | None => break,
-                        Some(Ok(Message::Ping(data))) => {
-                            if socket.send(Message::Pong(data)).await.is_err() {
-                                break;
-                            }
+                        Some(Ok(Message::Ping(data)))
+                            if socket.send(Message::Pong(data.clone())).await.is_err() =>
+                        {
+                            break;
                         }
                         _ => {} // Ignore others
                     }
                 }
                 // Handle internal broadcast messages (server -> client)
                 msg = rx.recv() => {
-                    if let Ok(msg) = msg {
-                        if let Ok(json) = serde_json::to_string(&msg) {
-                            if socket.send(Message::Text(json.into())).await.is_err() {
-                                break;
-                            }
-                        }
+                    if let Ok(msg) = msg
+                        && let Ok(json) = serde_json::to_string(&msg)
+                        && socket.send(Message::Text(json.into())).await.is_err()
+                    {
+                        break;
                     }
                 }
             }
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    fn state(admin_token: Option<&str>) -> Arc<AppState> {
+        let mut config: crate::config::GatewayConfig = serde_json::from_str(
+            r#"{"routes": [{"id": "api", "path": "/api", "backend_url": "http://api.internal"}]}"#,
+        )
+        .unwrap();
+        config.server.admin_token = admin_token.map(String::from);
+        Arc::new(AppState::new("unused.json".into(), config))
+    }
+
+    async fn send(
+        state: &Arc<AppState>,
+        method: &str,
+        id: &str,
+        token: Option<&str>,
+        body: &str,
+    ) -> StatusCode {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(format!("/api/routes/{}", id))
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let app = route_admin(state.clone()).with_state(state.clone());
+        let response = app
+            .oneshot(req.body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+        response.status()
+    }
+
+    const ROUTE: &str = r#"{"path": "/echo", "backend_url": "http://echo.internal"}"#;
+
+    #[tokio::test]
+    async fn test_route_changes_need_token() {
+        let disabled = state(None);
+        assert_eq!(
+            send(&disabled, "POST", "echo", Some("secret"), ROUTE).await,
+            StatusCode::FORBIDDEN
+        );
+
+        let state = state(Some("secret"));
+        assert_eq!(
+            send(&state, "POST", "echo", None, ROUTE).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            send(&state, "DELETE", "api", Some("wrong"), "").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(state.routes.load().routes().len(), 1);
+        assert!(!constant_time_eq(b"secret", b"secre"));
+    }
+
+    #[tokio::test]
+    async fn test_route_changes() {
+        let state = state(Some("secret"));
+        let token = Some("secret");
+        assert_eq!(
+            send(&state, "POST", "api", token, ROUTE).await,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            send(&state, "PUT", "missing", token, ROUTE).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            send(&state, "DELETE", "missing", token, "").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            send(
+                &state,
+                "POST",
+                "bad",
+                token,
+                r#"{"path": "echo", "backend_url": "x"}"#
+            )
+            .await,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(state.routes.load().routes().len(), 1);
+
+        assert_eq!(
+            send(&state, "POST", "echo", token, ROUTE).await,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            send(&state, "DELETE", "api", token, "").await,
+            StatusCode::NO_CONTENT
+        );
+        let routes = state.routes.load();
+        assert_eq!(routes.routes().len(), 1);
+        assert_eq!(routes.routes()[0].id, "echo");
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+
+use http::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::{config::HeaderRules, template::Template};
+
+/// Placeholders header values can use besides the path's captures
+pub const HEADER_VARS: [&str; 3] = ["client_ip", "route", "request_id"];
+
+/// Values of the `HEADER_VARS` for one request
+pub struct HeaderVars<'a> {
+    pub client_ip: &'a str,
+    pub route: &'a str,
+    pub request_id: &'a str,
+}
+
+impl<'a> HeaderVars<'a> {
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        match name {
+            "client_ip" => Some(self.client_ip),
+            "route" => Some(self.route),
+            "request_id" => Some(self.request_id),
+            _ => None,
+        }
+    }
+}
+
+/// A route's `HeaderRules` with names parsed and values compiled to
+/// templates
+#[derive(Debug)]
+pub struct CompiledHeaderRules {
+    rename: Vec<(HeaderName, HeaderName)>,
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, Template)>,
+    add: Vec<(HeaderName, Template)>,
+}
+
+impl CompiledHeaderRules {
+    pub fn compile(rules: &HeaderRules) -> Option<Self> {
+        let name = |name: &String| HeaderName::from_bytes(name.as_bytes()).ok();
+        let templates = |values: &BTreeMap<String, String>| {
+            values
+                .iter()
+                .map(|(header, value)| Some((name(header)?, Template::parse(value).ok()?)))
+                .collect::<Option<Vec<_>>>()
+        };
+        Some(Self {
+            rename: rules
+                .rename
+                .iter()
+                .map(|(from, to)| Some((name(from)?, name(to)?)))
+                .collect::<Option<_>>()?,
+            remove: rules.remove.iter().map(name).collect::<Option<_>>()?,
+            set: templates(&rules.set)?,
+            add: templates(&rules.add)?,
+        })
+    }
+
+    /// Renames, removes, sets and then adds headers, in that order, with
+    /// placeholders filled in by `lookup`. Values that render to something
+    /// that isn't a valid header value are skipped.
+    pub fn apply<'a>(&self, headers: &mut HeaderMap, lookup: impl Fn(&str) -> Option<&'a str>) {
+        for (from, to) in &self.rename {
+            let values: Vec<HeaderValue> = headers.get_all(from).iter().cloned().collect();
+            if values.is_empty() {
+                continue;
+            }
+            headers.remove(from);
+            headers.remove(to);
+            for value in values {
+                headers.append(to, value);
+            }
+        }
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        let render = |template: &Template| HeaderValue::from_str(&template.render(&lookup)).ok();
+        for (name, template) in &self.set {
+            if let Some(value) = render(template) {
+                headers.insert(name, value);
+            }
+        }
+        for (name, template) in &self.add {
+            if let Some(value) = render(template) {
+                headers.append(name, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_apply_in_order() {
+        let rules: HeaderRules = serde_json::from_str(
+            r#"{
+                "rename": {"X-Old": "X-New"},
+                "remove": ["X-Secret"],
+                "set": {"X-Route": "{route}", "X-Bad": "line\u0001break"},
+                "add": {"X-Trace": "{request_id}@{client_ip}"}
+            }"#,
+        )
+        .unwrap();
+        let rules = CompiledHeaderRules::compile(&rules).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.append("x-old", HeaderValue::from_static("a"));
+        headers.append("x-old", HeaderValue::from_static("b"));
+        headers.insert("x-new", HeaderValue::from_static("stale"));
+        headers.insert("x-secret", HeaderValue::from_static("s"));
+        headers.insert("x-route", HeaderValue::from_static("client"));
+        headers.insert("x-trace", HeaderValue::from_static("upstream"));
+        let vars = HeaderVars {
+            client_ip: "10.0.0.1",
+            route: "users",
+            request_id: "abc",
+        };
+        rules.apply(&mut headers, |name| vars.get(name));
+
+        let values = |name: &str| -> Vec<&str> {
+            headers
+                .get_all(name)
+                .iter()
+                .map(|value| value.to_str().unwrap())
+                .collect()
+        };
+        assert_eq!(values("x-old"), Vec::<&str>::new());
+        assert_eq!(values("x-new"), ["a", "b"]);
+        assert_eq!(values("x-secret"), Vec::<&str>::new());
+        assert_eq!(values("x-route"), ["users"]);
+        assert_eq!(values("x-bad"), Vec::<&str>::new());
+        assert_eq!(values("x-trace"), ["upstream", "abc@10.0.0.1"]);
+    }
+}
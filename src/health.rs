@@ -0,0 +1,170 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use reqwest::Client;
+use tokio::time::interval;
+use tracing::{info, warn};
+
+use crate::{
+    config::HealthCheckConfig,
+    models::{UpstreamHealthEvent, WsMessage},
+    state::AppState,
+};
+
+/// How often the checker looks for upstreams that are due a probe
+const TICK: Duration = Duration::from_millis(250);
+
+/// Probes the upstreams of every route with a `health_check` and marks them
+/// healthy or unhealthy
+pub struct HealthChecker {
+    state: Arc<AppState>,
+}
+
+impl HealthChecker {
+    pub fn new(state: Arc<AppState>) -> Self {
+        Self { state }
+    }
+
+    pub async fn start_checking(self: Arc<Self>) {
+        let checker = self.clone();
+        tokio::spawn(async move {
+            let mut interval = interval(TICK);
+            let mut next_probe: HashMap<String, Instant> = HashMap::new();
+
+            loop {
+                interval.tick().await;
+                let checks = checker.checks();
+
+                // Upstreams that lost their health check go back into rotation
+                for (url, upstream) in checker.state.upstreams.all() {
+                    if !checks.contains_key(&url) {
+                        upstream.reset_health();
+                        next_probe.remove(&url);
+                    }
+                }
+
+                let now = Instant::now();
+                for (url, (check, client)) in checks {
+                    if next_probe.get(&url).is_some_and(|&due| due > now) {
+                        continue;
+                    }
+                    next_probe.insert(url.clone(), now + Duration::from_millis(check.interval_ms));
+                    let checker = checker.clone();
+                    tokio::spawn(async move { checker.probe(url, check, client).await });
+                }
+            }
+        });
+    }
+
+    /// Health check for each upstream URL in the current routes, with the
+    /// client the route's requests use, so that gRPC upstreams are probed
+    /// over HTTP/2 as well. An upstream shared by several routes uses the
+    /// first route's check.
+    fn checks(&self) -> HashMap<String, (HealthCheckConfig, Client)> {
+        let routes = self.state.routes.load();
+        let mut checks = HashMap::new();
+        for route in routes.routes() {
+            let Some(check) = &route.health_check else {
+                continue;
+            };
+            let client = self
+                .state
+                .client(&routes.config().timeouts(route), route.grpc);
+            for target in route.targets() {
+                checks
+                    .entry(target.url)
+                    .or_insert_with(|| (check.clone(), client.clone()));
+            }
+        }
+        checks
+    }
+
+    async fn probe(&self, url: String, check: HealthCheckConfig, client: Client) {
+        let probe_url = format!("{}{}", url.trim_end_matches('/'), check.path);
+        let passed = match client
+            .get(&probe_url)
+            .timeout(Duration::from_millis(check.timeout_ms))
+            .send()
+            .await
+        {
+            Ok(response) => check.expected_status.contains(&response.status().as_u16()),
+            Err(_) => false,
+        };
+
+        let upstream = self.state.upstreams.get(&url);
+        let Some(healthy) = upstream.record_probe(passed, check.rise, check.fall) else {
+            return;
+        };
+        if healthy {
+            info!("Upstream {} is healthy again", url);
+        } else {
+            warn!("Upstream {} is unhealthy, taking it out of rotation", url);
+        }
+        let event = UpstreamHealthEvent::new(url, healthy);
+        let _ = self
+            .state
+            .metrics_store
+            .get_broadcaster()
+            .send(WsMessage::UpstreamHealth { event });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use http_body_util::Empty;
+    use hyper::{server::conn::http2, service::service_fn};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use serde_json::json;
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+
+    /// Serves 200s over HTTP/2 without an upgrade from HTTP/1, the way gRPC
+    /// servers do, returning its base URL
+    async fn h2c_backend() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(|_| async {
+                    Ok::<_, Infallible>(http::Response::new(Empty::<Bytes>::new()))
+                });
+                tokio::spawn(
+                    http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_grpc_upstreams_are_probed_over_http2() {
+        let url = h2c_backend().await;
+        let config = serde_json::from_value(json!({"routes": [{
+            "path": "/rpc",
+            "backend_url": url,
+            "grpc": true,
+            "health_check": {"fall": 1}
+        }]}))
+        .unwrap();
+        let state = Arc::new(AppState::new("unused.json".into(), config));
+        let checker = HealthChecker::new(state.clone());
+        let upstream = state.upstreams.get(&url);
+
+        let (check, client) = checker.checks().remove(&url).unwrap();
+        checker.probe(url.clone(), check.clone(), client).await;
+        assert!(upstream.is_healthy());
+        assert!(upstream.last_checked().is_some());
+
+        // An HTTP/1 probe can't reach it at all
+        let http1 = Client::builder().use_rustls_tls().build().unwrap();
+        checker.probe(url.clone(), check, http1).await;
+        assert!(!upstream.is_healthy());
+    }
+}
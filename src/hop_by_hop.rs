@@ -0,0 +1,110 @@
+use http::{
+    HeaderMap, HeaderName, HeaderValue, Version,
+    header::{
+        CONNECTION, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRANSFER_ENCODING, UPGRADE, VIA,
+    },
+};
+
+/// Headers that only describe one connection (RFC 9110 section 7.6.1),
+/// plus the ones authenticating with the gateway itself
+const HOP_BY_HOP: [HeaderName; 8] = [
+    CONNECTION,
+    HeaderName::from_static("proxy-connection"),
+    HeaderName::from_static("keep-alive"),
+    TE,
+    TRANSFER_ENCODING,
+    UPGRADE,
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+];
+
+/// Removes the hop-by-hop headers and any others the `Connection` header
+/// names, so they aren't passed on to the next hop
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+    for name in listed.iter().chain(&HOP_BY_HOP) {
+        headers.remove(name);
+    }
+}
+
+/// Whether the client said it accepts trailers with `TE: trailers`, which
+/// is passed on since gRPC servers rely on it
+pub fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// Adds this gateway to the `Via` header of a message received over
+/// `version`
+pub fn add_via(headers: &mut HeaderMap, version: Version, instance: &str) {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    if let Ok(value) = HeaderValue::from_str(&format!("{} {}", protocol, instance)) {
+        headers.append(VIA, value);
+    }
+}
+
+/// Whether the `Via` header shows the message already passed through
+/// `instance`
+pub fn has_looped(headers: &HeaderMap, instance: &str) -> bool {
+    headers
+        .get_all(VIA)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|hop| hop.split_whitespace().nth(1) == Some(instance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strips_connection_listed_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONNECTION,
+            HeaderValue::from_static("keep-alive, X-Session"),
+        );
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-session", HeaderValue::from_static("abc"));
+        headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        headers.insert("x-kept", HeaderValue::from_static("1"));
+        headers.insert(TE, HeaderValue::from_static("gzip, trailers"));
+        assert!(accepts_trailers(&headers));
+        strip_hop_by_hop(&mut headers);
+        assert!(!accepts_trailers(&headers));
+
+        let names: Vec<&str> = headers.keys().map(HeaderName::as_str).collect();
+        assert_eq!(names, ["x-kept"]);
+    }
+
+    #[test]
+    fn test_loop_detection() {
+        let mut headers = HeaderMap::new();
+        add_via(&mut headers, Version::HTTP_2, "gateway-other");
+        assert!(!has_looped(&headers, "gateway-self"));
+
+        add_via(&mut headers, Version::HTTP_11, "gateway-self");
+        assert_eq!(
+            headers.get_all(VIA).iter().collect::<Vec<_>>(),
+            ["2 gateway-other", "1.1 gateway-self"]
+        );
+        assert!(has_looped(&headers, "gateway-self"));
+    }
+}
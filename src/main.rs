@@ -1,21 +1,41 @@
+mod balancer;
+mod breaker;
+mod cli;
 mod config;
 mod error;
+mod forwarded;
+mod grpc;
 mod handlers;
+mod header_rules;
+mod health;
+mod hop_by_hop;
 mod metrics;
 mod models;
 mod proxy;
+mod reload;
+mod retry;
 mod router;
 mod state;
 mod storage;
+mod template;
+mod tunnel;
 
-use crate::handlers::{get_logs, get_metrics, get_routes, websocket_handler};
+use crate::cli::{Cli, Command, ServeArgs};
+use crate::config::GatewayConfig;
+use crate::handlers::{
+    get_logs, get_metrics, get_routes, get_upstreams, route_admin, websocket_handler,
+};
+use crate::health::HealthChecker;
 use crate::proxy::proxy_handler;
 use crate::state::AppState;
 
 use axum::Router;
 use axum::routing::get;
 use axum_client_ip::ClientIpSource;
+use clap::Parser;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::sync::Arc;
 use std::time::Duration;
 use tower::ServiceBuilder;
@@ -23,20 +43,32 @@ use tower_governor::GovernorLayer;
 use tower_governor::governor::GovernorConfigBuilder;
 use tracing::info;
 
-struct Server {}
+struct Server {
+    config_path: PathBuf,
+    config: GatewayConfig,
+    bind: String,
+    admin_bind: Option<String>,
+}
 
 impl Server {
     async fn run(self) -> Result<(), std::io::Error> {
-        tracing_subscriber::fmt::init();
-
-        let state = Arc::new(AppState::new());
+        let state = Arc::new(AppState::new(self.config_path.clone(), self.config.clone()));
 
         // Start metrics broadcasting
         state.metrics_collector.clone().start_broadcasting().await;
 
+        // Probe upstreams of routes with a health check
+        Arc::new(HealthChecker::new(state.clone()))
+            .start_checking()
+            .await;
+
+        // Pick up route changes from the config file and SIGHUP
+        reload::spawn_watchers(state.clone());
+
+        let rate_limit = &self.config.rate_limit;
         let governor_conf = GovernorConfigBuilder::default()
-            .per_second(2)
-            .burst_size(5)
+            .per_second(rate_limit.per_second)
+            .burst_size(rate_limit.burst_size)
             .finish()
             .unwrap();
 
@@ -50,43 +82,110 @@ impl Server {
             }
         });
 
-        let app = Router::new()
-            .route("/health", get(proxy::health_check))
+        let admin = Router::new()
             // NEW: API routes for monitoring dashboard
             .route("/api/metrics", get(get_metrics))
             .route("/api/logs", get(get_logs))
             .route("/api/routes", get(get_routes))
-            .route("/ws", get(websocket_handler))
-            .fallback(proxy_handler)
-            .layer(GovernorLayer::new(governor_conf))
+            .route("/api/upstreams", get(get_upstreams))
+            .merge(route_admin(state.clone()))
+            .route("/ws", get(websocket_handler));
+
+        let mut app = Router::new().route("/health", get(proxy::health_check));
+        if self.admin_bind.is_none() {
+            app = app.merge(admin.clone());
+        }
+        let mut app = app.fallback(proxy_handler);
+        if rate_limit.enabled {
+            app = app.layer(GovernorLayer::new(governor_conf));
+        }
+        let app = app
             .layer(
                 ServiceBuilder::new()
                     .layer(ClientIpSource::ConnectInfo.into_extension())
                     .layer(tower_http::trace::TraceLayer::new_for_http()),
             )
-            .with_state(state);
+            .with_state(state.clone());
 
-        let addr = "127.0.0.1:3000";
+        let listener = bind(&self.bind).await?;
+        info!("Gateway API server listening on {}", self.bind);
 
-        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-
-        info!("Gateway API server with monitoring listening on {}", addr);
-        info!("Monitoring dashboard API available at http://{}/api/", addr);
-        info!("WebSocket endpoint available at ws://{}/ws", addr);
+        let admin_server = match &self.admin_bind {
+            Some(admin_addr) => {
+                let admin_listener = bind(admin_addr).await?;
+                let admin = admin
+                    .layer(tower_http::trace::TraceLayer::new_for_http())
+                    .with_state(state);
+                Some(axum::serve(
+                    admin_listener,
+                    admin.into_make_service_with_connect_info::<SocketAddr>(),
+                ))
+            }
+            None => None,
+        };
+        let admin_addr = self.admin_bind.as_ref().unwrap_or(&self.bind);
+        info!(
+            "Monitoring dashboard API available at http://{}/api/",
+            admin_addr
+        );
+        info!("WebSocket endpoint available at ws://{}/ws", admin_addr);
         info!("Forwarding requests to backend");
 
-        axum::serve(
+        let server = axum::serve(
             listener,
             app.into_make_service_with_connect_info::<SocketAddr>(),
-        )
-        .await
+        );
+        match admin_server {
+            Some(admin_server) => tokio::try_join!(server, admin_server).map(|_| ()),
+            None => server.await,
+        }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let server = Server {};
+async fn bind(addr: &str) -> Result<tokio::net::TcpListener, std::io::Error> {
+    tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(|e| std::io::Error::new(e.kind(), format!("couldn't bind {}: {}", addr, e)))
+}
+
+async fn serve(config_path: PathBuf, args: ServeArgs) -> ExitCode {
+    let config = match config::load_config(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let server = Server {
+        bind: args
+            .bind
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| config.server.bind.clone()),
+        admin_bind: args
+            .admin_bind
+            .map(|addr| addr.to_string())
+            .or_else(|| config.server.admin_bind.clone()),
+        config_path,
+        config,
+    };
     if let Err(e) = server.run().await {
         eprintln!("Server Error, {}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt::init();
+
+    let cli = Cli::parse();
+    match &cli.command {
+        None => serve(cli.config.clone(), ServeArgs::default()).await,
+        Some(Command::Serve(args)) => serve(cli.config.clone(), args.clone()).await,
+        Some(Command::Validate) => cli::validate(&cli),
+        Some(Command::Routes) => cli::print_routes(&cli),
+        Some(Command::Match(args)) => cli::print_match(&cli, args),
     }
 }
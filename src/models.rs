@@ -12,6 +12,34 @@ pub struct RequestLog {
     pub status: u16,
     pub response_time: Duration,
     pub client_ip: String,
+    /// Path requested from the backend after rewriting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_path: Option<String>,
+    /// Upstream that served the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    /// Every attempt at reaching the backend, when the request was retried
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<AttemptLog>,
+    /// Status from the `grpc-status` trailer of a gRPC response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grpc_status: Option<u32>,
+    /// No route matched the request, so it went to `default_backend` or
+    /// got a 404
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unmatched: bool,
+}
+
+/// One attempt at sending a request to its backend
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttemptLog {
+    pub upstream: String,
+    /// Backend response status, if it responded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub response_time: Duration,
 }
 
 impl RequestLog {
@@ -29,18 +57,182 @@ impl RequestLog {
             status,
             response_time,
             client_ip,
+            upstream_path: None,
+            upstream: None,
+            attempts: Vec::new(),
+            grpc_status: None,
+            unmatched: false,
+        }
+    }
+}
+
+/// Requests for one path that no route matched
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnmatchedPath {
+    pub path: String,
+    pub count: u64,
+    /// Client that most recently requested it
+    pub last_client_ip: String,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// A WebSocket tunnel to an upstream, recorded once it closes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TunnelLog {
+    /// When the tunnel opened
+    pub timestamp: DateTime<Utc>,
+    pub route: String,
+    pub upstream: String,
+    pub path: String,
+    pub client_ip: String,
+    pub duration: Duration,
+    /// Message bytes from the client to the upstream
+    pub bytes_sent: u64,
+    /// Message bytes from the upstream to the client
+    pub bytes_received: u64,
+    pub closed_by: TunnelClose,
+}
+
+impl TunnelLog {
+    pub fn new(route: String, upstream: String, path: String, client_ip: String) -> Self {
+        TunnelLog {
+            timestamp: Utc::now(),
+            route,
+            upstream,
+            path,
+            client_ip,
+            duration: Duration::zero(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            closed_by: TunnelClose::Client,
+        }
+    }
+}
+
+/// Why a WebSocket tunnel closed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelClose {
+    Client,
+    Upstream,
+    IdleTimeout,
+    MaxLifetime,
+}
+
+/// What caused a config reload
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ReloadTrigger {
+    FileChange,
+    Sighup,
+    AdminApi,
+}
+
+/// Outcome of a config reload
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReloadEvent {
+    pub timestamp: DateTime<Utc>,
+    pub trigger: ReloadTrigger,
+    pub success: bool,
+    pub routes: Option<usize>,
+    pub error: Option<String>,
+}
+
+impl ReloadEvent {
+    pub fn new(trigger: ReloadTrigger, result: &Result<usize, String>) -> Self {
+        ReloadEvent {
+            timestamp: Utc::now(),
+            trigger,
+            success: result.is_ok(),
+            routes: result.as_ref().ok().copied(),
+            error: result.as_ref().err().cloned(),
+        }
+    }
+}
+
+/// An upstream's health check result flipped
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpstreamHealthEvent {
+    pub timestamp: DateTime<Utc>,
+    pub upstream: String,
+    pub healthy: bool,
+}
+
+impl UpstreamHealthEvent {
+    pub fn new(upstream: String, healthy: bool) -> Self {
+        UpstreamHealthEvent {
+            timestamp: Utc::now(),
+            upstream,
+            healthy,
         }
     }
 }
 
+/// State of an upstream's circuit breaker
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests go through
+    Closed,
+    /// Requests fail fast
+    Open,
+    /// A few trial requests go through to see if the upstream recovered
+    HalfOpen,
+}
+
+/// An upstream's circuit breaker changed state
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CircuitBreakerEvent {
+    pub timestamp: DateTime<Utc>,
+    pub upstream: String,
+    pub state: CircuitState,
+}
+
+impl CircuitBreakerEvent {
+    pub fn new(upstream: String, state: CircuitState) -> Self {
+        CircuitBreakerEvent {
+            timestamp: Utc::now(),
+            upstream,
+            state,
+        }
+    }
+}
+
+/// Current state of one upstream, for `/api/upstreams`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpstreamStatus {
+    pub url: String,
+    /// Ids of the routes that send requests to it
+    pub routes: Vec<String>,
+    pub health_checked: bool,
+    pub healthy: bool,
+    pub last_checked: Option<DateTime<Utc>>,
+    /// When outlier detection puts it back into rotation, if it is ejected
+    pub ejected_until: Option<DateTime<Utc>>,
+    pub circuit: CircuitState,
+    pub outstanding: u64,
+}
+
 /// Summary metrics of requests
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SummaryMetrics {
     pub total_requests: u64,
     pub total_errors: u64,
+    /// Requests no route matched, including ones sent to `default_backend`
+    pub unmatched_requests: u64,
+    /// The paths no route matched that were requested most often
+    pub top_unmatched: Vec<UnmatchedPath>,
+    /// Requests that timed out waiting for a backend
+    pub total_timeouts: u64,
     pub active_connections: u64,
     pub recent_logs: Vec<RequestLog>,
     pub route_stats: HashMap<String, u64>,
+    /// WebSocket tunnels opened to upstreams
+    pub total_tunnels: u64,
+    pub active_tunnels: u64,
+    pub recent_tunnels: Vec<TunnelLog>,
+    /// Circuit breaker state of each upstream whose breaker has tripped
+    pub circuit_breakers: HashMap<String, CircuitState>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,6 +240,9 @@ pub struct SummaryMetrics {
 pub enum WsMessage {
     NewLog { log: RequestLog },
     MetricsUpdate { metrics: SummaryMetrics },
+    ConfigReload { event: ReloadEvent },
+    UpstreamHealth { event: UpstreamHealthEvent },
+    CircuitBreaker { event: CircuitBreakerEvent },
 }
 
 #[derive(Debug, Deserialize)]
@@ -1,85 +1,518 @@
-use std::sync::Arc;
+use std::{
+    io,
+    net::IpAddr,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
 use axum::{
     Json,
-    body::Body,
+    body::{Body, Bytes, HttpBody},
     extract::State,
     response::{IntoResponse, Response},
 };
 use axum_client_ip::ClientIp;
-use chrono::Utc;
-use http::{HeaderMap, Method, Request, StatusCode};
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
+use http::{
+    HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode,
+    header::{CONTENT_LENGTH, CONTENT_TYPE, HOST, TE},
+};
+use http_body::Frame;
+use http_body_util::{BodyStream, StreamBody};
 use reqwest::RequestBuilder;
 use serde_json::json;
-use tracing::info;
+use sync_wrapper::SyncStream;
+use tokio::time::{Instant, timeout_at};
+use tracing::{info, warn};
 
-use crate::{error::ProxyError, models::RequestLog, router::match_route, state::AppState};
+use crate::{
+    balancer::{InFlight, UpstreamState},
+    config::{RetryOn, Upstream},
+    error::ProxyError,
+    forwarded::add_forwarding_headers,
+    grpc::{self, is_grpc},
+    header_rules::HeaderVars,
+    hop_by_hop::{accepts_trailers, add_via, has_looped, strip_hop_by_hop},
+    models::{AttemptLog, RequestLog, TunnelLog},
+    retry::{backoff, error_kind},
+    router::{RouteMatch, RouteTable},
+    state::AppState,
+    storage::MetricsStore,
+    tunnel::{self, is_websocket_upgrade},
+};
 
 pub async fn proxy_handler(
     State(state): State<Arc<AppState>>,
     ClientIp(ip): ClientIp,
     method: Method,
-    headers: HeaderMap,
     req: Request<Body>,
 ) -> Result<Response, ProxyError> {
     let start_time = Utc::now();
     let uri = req.uri().clone();
     let path = uri.path();
-    let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
 
     // println!("Client IP: {}", ip);
     // println!("X-Forwarded-For: {:?}", headers.get("x-forwarded-for"));
-    let matched = match_route(&state.routes, path).unwrap();
+    let routes = state.routes.load();
+    let route_matched = routes.match_route(&req);
+    let unmatched = route_matched.is_none();
+    let matched = route_matched.or_else(|| routes.default_match(path));
 
-    let backend_url = &matched.backend_url;
-    let backend_path = path.strip_prefix(&matched.path).unwrap();
+    let upstream_path = matched.as_ref().map(RouteMatch::upstream_path);
+    let max_response_body = matched
+        .as_ref()
+        .and_then(|matched| routes.config().max_response_body(matched.route));
+    let grpc_request = is_grpc(req.headers());
+    let mut attempts = Vec::new();
+    let mut result = match &matched {
+        Some(matched) => forward(&state, &routes, matched, ip, req, &mut attempts).await,
+        None => Err(ProxyError::NoRoute(path.to_string())),
+    };
 
-    let backend_uri = format!("{}{}{}", backend_url, backend_path, query);
-    info!("Proxying {} {} -> {}", req.method(), req.uri(), backend_uri);
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(e) => {
+            e.log();
+            e.status()
+        }
+    };
+    if let Err(ProxyError::Timeout(_)) = &result {
+        state.metrics_store.add_timeout();
+    }
 
-    let body_bytes = axum::body::to_bytes(req.into_body(), usize::MAX)
-        .await
-        .map_err(|e| ProxyError::BodyError(e.to_string()))?;
+    // Record metrics
+    let end_time = Utc::now();
+    let response_time = end_time.signed_duration_since(start_time);
+    let status_code = status.as_u16();
 
-    let mut client_req = state.client.request(method.clone(), backend_uri);
+    let mut log = RequestLog::new(
+        method.to_string(),
+        path.to_string(),
+        status_code,
+        response_time,
+        ip.to_string(),
+    );
+    log.upstream_path = upstream_path;
+    if unmatched {
+        log.unmatched = true;
+        state.metrics_store.add_unmatched(&log.path, &log.client_ip);
+    }
+    log.upstream = attempts.last().map(|attempt| attempt.upstream.clone());
+    if attempts.len() > 1 {
+        log.attempts = attempts;
+    }
 
-    if !body_bytes.is_empty() {
-        client_req = client_req.body(body_bytes);
+    // gRPC clients get errors as a gRPC status. The log keeps the HTTP
+    // status so that they still count as errors.
+    if grpc_request && let Err(e) = &result {
+        let response = grpc::error_response(e);
+        log.grpc_status = grpc::status(response.headers());
+        result = Ok(response);
     }
 
-    for (key, value) in headers.iter() {
-        let key_str = key.as_str();
-        if key_str == "host"
-            || key_str == "connection"
-            || key_str == "keep-alive"
-            || key_str == "proxy-authenticate"
-            || key_str == "proxy-authorization"
-            || key_str == "te"
-            || key_str == "trailers"
-            || key_str == "transfer-encoding"
-            || key_str == "upgrade"
+    // Event streams and gRPC calls aren't complete until the body ends, so
+    // they are logged then. A gRPC call's status usually comes in the
+    // trailers, unless the response has no body. So are bodies of unknown
+    // size that may yet be cut off at the size limit.
+    if let Ok(response) = &mut result
+        && (is_event_stream(response.headers())
+            || is_grpc(response.headers())
+            || (max_response_body.is_some() && content_length(response.headers()).is_none()))
+        && log.grpc_status.is_none()
+    {
+        log.grpc_status = grpc::status(response.headers());
+        let mut pending = PendingLog {
+            log: Some(log),
+            start_time,
+            failure: response.extensions_mut().remove::<BodyFailure>(),
+            metrics: state.metrics_store.clone(),
+        };
+        let body = BodyStream::new(std::mem::take(response.body_mut()));
+        *response.body_mut() = Body::new(StreamBody::new(body.map(move |frame| {
+            if let Ok(frame) = &frame
+                && let Some(trailers) = frame.trailers_ref()
+            {
+                pending.set_grpc_status(trailers);
+            }
+            frame
+        })));
+        return result;
+    }
+
+    state.metrics_store.add_request(log);
+
+    result
+}
+
+/// Request log held back until the response body it belongs to is done or
+/// dropped
+struct PendingLog {
+    log: Option<RequestLog>,
+    start_time: DateTime<Utc>,
+    /// Set if the body was cut off part way through
+    failure: Option<BodyFailure>,
+    metrics: Arc<MetricsStore>,
+}
+
+/// Why a response body that was already being passed on to the client
+/// failed. The status has been sent by then, so the failure only shows in
+/// the request log.
+#[derive(Clone, Default)]
+struct BodyFailure(Arc<OnceLock<ProxyError>>);
+
+impl PendingLog {
+    fn set_grpc_status(&mut self, trailers: &HeaderMap) {
+        if let Some(log) = &mut self.log
+            && let Some(status) = grpc::status(trailers)
         {
-            continue;
+            log.grpc_status = Some(status);
+        }
+    }
+}
+
+impl Drop for PendingLog {
+    fn drop(&mut self) {
+        let Some(mut log) = self.log.take() else {
+            return;
+        };
+        log.response_time = Utc::now().signed_duration_since(self.start_time);
+        match self.failure.as_ref().and_then(|failure| failure.0.get()) {
+            Some(e) => {
+                warn!(
+                    "Response to {} {} cut off after {}ms: {}",
+                    log.method,
+                    log.path,
+                    log.response_time.num_milliseconds(),
+                    e.message()
+                );
+                log.status = e.status().as_u16();
+            }
+            None => info!(
+                "Response to {} {} ended after {}ms",
+                log.method,
+                log.path,
+                log.response_time.num_milliseconds()
+            ),
+        }
+        self.metrics.add_request(log);
+    }
+}
+
+/// Whether the message is an event stream, going by its content type
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|essence| essence.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+/// Sends the request to one of the matched route's upstreams, retrying it
+/// if the route allows, and streams back the response. Each attempt is
+/// added to `attempts`.
+async fn forward(
+    state: &AppState,
+    routes: &RouteTable,
+    matched: &RouteMatch<'_>,
+    client_ip: IpAddr,
+    req: Request<Body>,
+    attempts: &mut Vec<AttemptLog>,
+) -> Result<Response, ProxyError> {
+    let (mut parts, body) = req.into_parts();
+    let (method, headers) = (&parts.method, &parts.headers);
+    let config = routes.config();
+    if has_looped(headers, &state.instance) {
+        return Err(ProxyError::LoopDetected);
+    }
+
+    let max_request_body = config.max_request_body(matched.route);
+    let max_response_body = config.max_response_body(matched.route);
+    if let Some(limit) = max_request_body
+        && content_length(headers).is_some_and(|length| length > limit)
+    {
+        return Err(ProxyError::PayloadTooLarge(limit));
+    }
+
+    let timeouts = config.timeouts(matched.route);
+    let client = state.client(&timeouts, matched.route.grpc);
+
+    let retry = matched
+        .route
+        .retry
+        .as_ref()
+        .filter(|policy| policy.allows_method(method));
+
+    // Stream the body through rather than buffering it; reqwest only polls
+    // for more of it as the backend accepts data. A retried request has to
+    // send its body again though, so small bodies are buffered for routes
+    // that retry.
+    let request_too_large = Arc::new(AtomicBool::new(false));
+    let mut buffered = None;
+    let mut streamed = None;
+    if body.size_hint().exact() != Some(0) {
+        match content_length(headers) {
+            Some(length) if retry.is_some() && length <= RETRY_BUFFER_LIMIT => {
+                let bytes = axum::body::to_bytes(body, RETRY_BUFFER_LIMIT as usize)
+                    .await
+                    .map_err(|e| ProxyError::BodyError(e.to_string()))?;
+                buffered = Some(bytes);
+            }
+            _ => {
+                // Pass frames rather than bytes so that trailers, which
+                // client-streaming gRPC calls rely on, get through. reqwest
+                // wants a Sync body, which only the wrapper makes it.
+                let frames = limit_frames(
+                    BodyStream::new(body),
+                    max_request_body,
+                    request_too_large.clone(),
+                );
+                streamed = Some(reqwest::Body::wrap(StreamBody::new(SyncStream::new(
+                    frames,
+                ))));
+            }
         }
+    }
+    // Streamed bodies can't be sent again, so those requests get one attempt
+    let replayable = streamed.is_none();
 
-        client_req = client_req.header(key, value);
+    let mut upstream_headers = headers.clone();
+    upstream_headers.remove(HOST);
+    strip_hop_by_hop(&mut upstream_headers);
+    if accepts_trailers(headers) {
+        upstream_headers.insert(TE, HeaderValue::from_static("trailers"));
     }
+    add_via(&mut upstream_headers, parts.version, &state.instance);
+
+    if config.forwarded.enabled {
+        let host = headers
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| parts.uri.authority().map(|authority| authority.as_str()));
+        add_forwarding_headers(
+            &mut upstream_headers,
+            host,
+            client_ip,
+            routes.trusted_proxies(),
+        );
+    }
+
+    // Keep the id of a request that already has one, so that it can be
+    // followed across services
+    let request_id = headers
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+    let client_ip_text = client_ip.to_string();
+    let header_vars = HeaderVars {
+        client_ip: &client_ip_text,
+        route: &matched.route.id,
+        request_id: &request_id,
+    };
+    matched.rewrite_request_headers(&mut upstream_headers, &header_vars);
 
     //    info!("Headers sent by client:");
     //    for (key, value) in headers.iter() {
     //        info!("  {}: {:?}", key, value);
     //    }
 
-    let response = RequestBuilder::send(client_req).await.unwrap();
+    if is_websocket_upgrade(headers) {
+        let (upstream, upstream_state) = pick_upstream(state, matched, headers, client_ip)?;
+        let backend_uri = matched.backend_uri(&upstream.url, parts.uri.query());
+        info!("Tunneling WebSocket {} -> {}", parts.uri, backend_uri);
+
+        let log = TunnelLog::new(
+            matched.route.id.clone(),
+            upstream.url.clone(),
+            parts.uri.path().to_string(),
+            client_ip.to_string(),
+        );
+        let in_flight = InFlight::new(upstream_state.clone());
+        let started = Utc::now();
+        let result = tunnel::open(
+            &mut parts,
+            &backend_uri,
+            upstream_headers,
+            timeouts,
+            in_flight,
+            log,
+            state.metrics_store.clone(),
+        )
+        .await;
+        attempts.push(AttemptLog {
+            upstream: upstream.url.clone(),
+            status: result.as_ref().ok().map(|r| r.status().as_u16()),
+            error: result.as_ref().err().map(ProxyError::message),
+            response_time: Utc::now().signed_duration_since(started),
+        });
+        // Only the handshake counts; how long the tunnel then lasts is up to
+        // the two ends
+        let client_failed = matches!(result, Err(ProxyError::UpgradeError(_)));
+        let failed = !client_failed
+            && match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+        record_outcome(
+            state,
+            matched,
+            &upstream.url,
+            &upstream_state,
+            failed,
+            client_failed,
+        );
+        return result;
+    }
+
+    if retry.is_some() {
+        state.retry_budgets.record_request(&matched.route.id);
+    }
+    // `total` covers the request as a whole, retries and backoff included
+    let deadline = timeouts.total.map(|total| Instant::now() + total);
+    let mut attempt = 1;
+    let (result, in_flight) = loop {
+        let (upstream, upstream_state) = pick_upstream(state, matched, headers, client_ip)?;
+        let backend_uri = matched.backend_uri(&upstream.url, parts.uri.query());
+        info!("Proxying {} {} -> {}", method, parts.uri, backend_uri);
+
+        let mut client_req = client
+            .request(method.clone(), backend_uri)
+            .headers(upstream_headers.clone());
+        if let Some(bytes) = &buffered {
+            client_req = client_req.body(bytes.clone());
+        } else if let Some(body) = streamed.take() {
+            client_req = client_req.body(body);
+        }
+
+        let in_flight = InFlight::new(upstream_state.clone());
+        let started = Utc::now();
+        let started_at = Instant::now();
+        let wait = within(timeouts.read_idle, deadline);
+        let result = match timeout_at(wait, RequestBuilder::send(client_req)).await {
+            Ok(result) => result.map_err(AttemptError::Request),
+            Err(_) => Err(AttemptError::TimedOut(wait - started_at)),
+        };
+        attempts.push(AttemptLog {
+            upstream: upstream.url.clone(),
+            status: result.as_ref().ok().map(|r| r.status().as_u16()),
+            error: result.as_ref().err().map(AttemptError::message),
+            response_time: Utc::now().signed_duration_since(started),
+        });
+
+        // A body rejected for its size is the client's failure, not the
+        // upstream's
+        let client_failed = request_too_large.load(Ordering::Relaxed);
+        let failed = !client_failed
+            && match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+        record_outcome(
+            state,
+            matched,
+            &upstream.url,
+            &upstream_state,
+            failed,
+            client_failed,
+        );
+
+        let Some(policy) = retry.filter(|_| replayable) else {
+            break (result, in_flight);
+        };
+        let retryable = match &result {
+            Ok(response) => policy.on_status.contains(&response.status().as_u16()),
+            Err(AttemptError::Request(e)) => policy.on_errors.contains(&error_kind(e)),
+            Err(AttemptError::TimedOut(_)) => policy.on_errors.contains(&RetryOn::Timeout),
+        };
+        if !retryable
+            || attempt >= policy.attempts
+            || !state.retry_budgets.try_retry(&matched.route.id, policy)
+        {
+            break (result, in_flight);
+        }
+
+        let delay = backoff(policy, attempt);
+        if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+            info!(
+                "Not retrying {} on route `{}` after attempt {} failed, its total timeout would pass first",
+                method, matched.route.id, attempt
+            );
+            break (result, in_flight);
+        }
+        info!(
+            "Retrying {} on route `{}` in {:?} after attempt {} failed",
+            method, matched.route.id, delay, attempt
+        );
+        // The failed attempt is done with its upstream
+        drop(result);
+        drop(in_flight);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    };
+
+    let response = result.map_err(|e| {
+        match (max_request_body, e) {
+            // A body without a Content-Length only turns out to be too large
+            // part way through forwarding it
+            (Some(limit), _) if request_too_large.load(Ordering::Relaxed) => {
+                ProxyError::PayloadTooLarge(limit)
+            }
+            (_, e @ AttemptError::TimedOut(_)) => ProxyError::Timeout(e.message()),
+            (_, AttemptError::Request(e)) if e.is_timeout() => ProxyError::Timeout(error_chain(&e)),
+            (_, AttemptError::Request(e)) => ProxyError::BackendError(error_chain(&e)),
+        }
+    })?;
 
     // info!("Version: {:?}", response.version());
 
-    let status = response.status();
-    let response_headers = response.headers().clone();
-    let body_bytes = response
-        .bytes()
-        .await
-        .map_err(|e| ProxyError::BackendError(e.to_string()))?;
+    // Read the body as frames rather than bytes so that trailers get through
+    let (response, body) = http::Response::from(response).into_parts();
+    let status = response.status;
+    let mut response_headers = response.headers;
+    strip_hop_by_hop(&mut response_headers);
+    add_via(&mut response_headers, response.version, &state.instance);
+    matched.rewrite_response_headers(&mut response_headers, &header_vars);
+    if let Some(limit) = max_response_body
+        && content_length(&response_headers).is_some_and(|length| length > limit)
+    {
+        return Err(ProxyError::ResponseTooLarge(limit));
+    }
+
+    // Event streams stay open indefinitely, with gaps between events
+    let (idle, deadline) = if is_event_stream(&response_headers) {
+        (timeouts.stream_idle, None)
+    } else {
+        (timeouts.read_idle, deadline)
+    };
+    let response_too_large = Arc::new(AtomicBool::new(false));
+    // Without a Content-Length a body's size is only known once it ends.
+    // Reading it all ahead would turn an oversized body into a 502, but
+    // would also hold back every chunked or long-poll response on the route
+    // until it ends, so such bodies are passed on as they arrive and cut off
+    // at the limit instead, and the request is logged as failed.
+    let frames = limit_time(
+        limit_frames(
+            BodyStream::new(body),
+            max_response_body,
+            response_too_large.clone(),
+        ),
+        idle,
+        deadline,
+    );
+    let body_error = {
+        let response_too_large = response_too_large.clone();
+        move |e: &io::Error| match max_response_body {
+            Some(limit) if response_too_large.load(Ordering::Relaxed) => {
+                ProxyError::ResponseTooLarge(limit)
+            }
+            _ if is_timeout(e) => ProxyError::Timeout(error_chain(e)),
+            _ => ProxyError::BackendError(error_chain(e)),
+        }
+    };
 
     let mut axum_response = Response::builder().status(status);
 
@@ -87,26 +520,206 @@ pub async fn proxy_handler(
         axum_response = axum_response.header(key, value);
     }
 
-    let response = axum_response
-        .body(Body::from(body_bytes))
-        .map_err(|e| ProxyError::ResponseError(e.to_string()))?;
+    let failure = BodyFailure::default();
+    let body_failure = failure.clone();
+    let metrics = state.metrics_store.clone();
+    let frames = frames.map(move |frame| {
+        // The request is outstanding on the upstream until its body is
+        // done
+        let _in_flight = &in_flight;
+        if let Err(e) = &frame {
+            let e = body_error(e);
+            if let ProxyError::Timeout(_) = e {
+                metrics.add_timeout();
+            }
+            let _ = body_failure.0.set(e);
+        }
+        frame
+    });
+    axum_response
+        .extension(failure)
+        .body(Body::new(StreamBody::new(frames)))
+        .map_err(|e| ProxyError::ResponseError(e.to_string()))
+}
 
-    // Record metrics
-    let end_time = Utc::now();
-    let response_time = end_time.signed_duration_since(start_time);
-    let status_code = status.as_u16();
+/// Picks one of the matched route's upstreams and, if the route has a
+/// circuit breaker, has the upstream's breaker admit the request
+fn pick_upstream<'a>(
+    state: &AppState,
+    matched: &'a RouteMatch<'_>,
+    headers: &HeaderMap,
+    client_ip: IpAddr,
+) -> Result<(&'a Upstream, Arc<UpstreamState>), ProxyError> {
+    let Some(upstream) = matched
+        .balancer()
+        .pick(&state.upstreams, headers, client_ip)
+    else {
+        let tripped = matched.route.circuit_breaker.is_some()
+            && matched
+                .balancer()
+                .targets()
+                .iter()
+                .any(|target| !state.upstreams.get(&target.url).breaker().allows());
+        return Err(if tripped {
+            ProxyError::CircuitOpen(matched.route.id.clone())
+        } else {
+            ProxyError::NoHealthyUpstream(matched.route.id.clone())
+        });
+    };
+    let upstream_state = state.upstreams.get(&upstream.url);
+    if let Some(breaker) = &matched.route.circuit_breaker {
+        let (admitted, transition) = upstream_state.breaker().admit(breaker);
+        if let Some(circuit) = transition {
+            info!(
+                "Circuit breaker for upstream {} is now {:?}",
+                upstream.url, circuit
+            );
+            state
+                .metrics_store
+                .set_circuit_state(&upstream.url, circuit);
+        }
+        if !admitted {
+            return Err(ProxyError::CircuitOpen(matched.route.id.clone()));
+        }
+    }
+    Ok((upstream, upstream_state))
+}
 
-    let log = RequestLog::new(
-        method.to_string(),
-        path.to_string(),
-        status_code,
-        response_time,
-        ip.to_string(),
-    );
+/// Feeds whether a request to `url` failed to the route's circuit breaker
+/// and outlier detection. Requests that failed through the client's fault
+/// (`client_failed`) count as successes for the breaker and aren't counted
+/// by outlier detection.
+fn record_outcome(
+    state: &AppState,
+    matched: &RouteMatch<'_>,
+    url: &str,
+    upstream_state: &UpstreamState,
+    failed: bool,
+    client_failed: bool,
+) {
+    if let Some(breaker) = &matched.route.circuit_breaker
+        && let Some(circuit) = upstream_state.breaker().record(failed, breaker)
+    {
+        warn!("Circuit breaker for upstream {} is now {:?}", url, circuit);
+        state.metrics_store.set_circuit_state(url, circuit);
+    }
+    if let Some(outlier) = &matched.route.outlier_detection
+        && !client_failed
+        && let Some(ejection) =
+            matched
+                .balancer()
+                .record_outcome(&state.upstreams, url, failed, outlier)
+    {
+        warn!(
+            "Upstream {} failed {} requests in a row, ejecting it for {:?}",
+            url, outlier.consecutive_failures, ejection
+        );
+    }
+}
 
-    state.metrics_store.add_request(log);
+/// Why an attempt got no response from the upstream
+enum AttemptError {
+    Request(reqwest::Error),
+    /// No response within this long
+    TimedOut(Duration),
+}
 
-    Ok(response)
+impl AttemptError {
+    fn message(&self) -> String {
+        match self {
+            AttemptError::Request(e) => error_chain(e),
+            AttemptError::TimedOut(wait) => format!("no response within {:?}", wait),
+        }
+    }
+}
+
+/// The earlier of `wait` from now and `deadline`
+fn within(wait: Duration, deadline: Option<Instant>) -> Instant {
+    let wait = Instant::now() + wait;
+    deadline.map_or(wait, |deadline| wait.min(deadline))
+}
+
+/// Whether reading a body failed because a timeout passed
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::TimedOut
+}
+
+/// Passes a body's frames through, failing it once `idle` passes without
+/// one or once `deadline` passes
+fn limit_time<S>(
+    stream: S,
+    idle: Duration,
+    deadline: Option<Instant>,
+) -> impl Stream<Item = Result<Frame<Bytes>, io::Error>>
+where
+    S: Stream<Item = Result<Frame<Bytes>, io::Error>> + Send + 'static,
+{
+    futures_util::stream::unfold(Some(stream.boxed()), move |stream| async move {
+        let mut stream = stream?;
+        let started = Instant::now();
+        let wait = within(idle, deadline);
+        match timeout_at(wait, stream.next()).await {
+            Ok(frame) => Some((frame?, Some(stream))),
+            Err(_) => Some((
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no data from the backend within {:?}", wait - started),
+                )),
+                None,
+            )),
+        }
+    })
+}
+
+/// reqwest's errors only describe the outermost failure, e.g. "error sending
+/// request", so include their causes
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Largest request body buffered so that it can be sent again on a retry
+const RETRY_BUFFER_LIMIT: u64 = 64 * 1024;
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// Passes a body's frames through, failing it once more than `limit` bytes
+/// of data have gone by and setting `exceeded`. Failing the stream aborts
+/// the transfer if the status has been sent by then.
+fn limit_frames<S, E>(
+    stream: S,
+    limit: Option<u64>,
+    exceeded: Arc<AtomicBool>,
+) -> impl Stream<Item = Result<Frame<Bytes>, io::Error>>
+where
+    S: Stream<Item = Result<Frame<Bytes>, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut seen: u64 = 0;
+    stream.map(move |frame| {
+        let frame = frame.map_err(io::Error::other)?;
+        seen += frame.data_ref().map_or(0, |data| data.len() as u64);
+        if let Some(limit) = limit
+            && seen > limit
+        {
+            exceeded.store(true, Ordering::Relaxed);
+            return Err(io::Error::other(format!(
+                "body is larger than {} bytes",
+                limit
+            )));
+        }
+        Ok(frame)
+    })
 }
 
 pub async fn health_check(ClientIp(_ip): ClientIp) -> impl IntoResponse {
@@ -118,3 +731,688 @@ pub async fn health_check(ClientIp(_ip): ClientIp) -> impl IntoResponse {
         })),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Router,
+        extract::ConnectInfo,
+        routing::{get, post},
+    };
+    use axum_client_ip::ClientIpSource;
+    use http::header::{ACCEPT, CONNECTION, VIA};
+    use http_body_util::BodyExt;
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::{
+        net::TcpListener,
+        sync::{Notify, mpsc},
+        time::timeout,
+    };
+    use tower::ServiceExt;
+
+    /// Serves `app` on a free local port, returning its base URL
+    async fn backend(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    /// The proxy alone, without rate limiting or the admin API
+    fn gateway(config: serde_json::Value) -> Router {
+        gateway_with_state(config).0
+    }
+
+    fn gateway_with_state(config: serde_json::Value) -> (Router, Arc<AppState>) {
+        let config = serde_json::from_value(config).unwrap();
+        let state = Arc::new(AppState::new("unused.json".into(), config));
+        let gateway = Router::new()
+            .fallback(proxy_handler)
+            .layer(ClientIpSource::ConnectInfo.into_extension())
+            .with_state(state.clone());
+        (gateway, state)
+    }
+
+    /// Serves `gateway` on a free local port like the server does, for
+    /// tests that need a real connection
+    async fn serve_gateway(gateway: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let service = gateway.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, service).await.unwrap()
+        });
+        addr
+    }
+
+    async fn send(gateway: &Router, mut req: Request<Body>) -> Response {
+        let peer: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        req.extensions_mut().insert(ConnectInfo(peer));
+        gateway.clone().oneshot(req).await.unwrap()
+    }
+
+    /// A request body fed chunk by chunk through the returned sender
+    fn channel_body() -> (mpsc::Sender<&'static str>, Body) {
+        let (tx, rx) = mpsc::channel(1);
+        let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+            let chunk = rx.recv().await?;
+            Some((Ok::<_, io::Error>(Bytes::from(chunk)), rx))
+        });
+        (tx, Body::from_stream(stream))
+    }
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn test_unmatched_requests() {
+        let (gateway, state) = gateway_with_state(json!({
+            "routes": [{"path": "/api", "backend_url": "http://127.0.0.1:9"}]
+        }));
+
+        for _ in 0..2 {
+            let response = send(
+                &gateway,
+                Request::get("/missing").body(Body::empty()).unwrap(),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, "No route matches /missing");
+        }
+        let metrics = state.metrics_store.get_metrics();
+        assert_eq!(metrics.unmatched_requests, 2);
+        assert_eq!(metrics.top_unmatched[0].path, "/missing");
+        assert_eq!(metrics.top_unmatched[0].count, 2);
+        assert_eq!(metrics.top_unmatched[0].last_client_ip, "127.0.0.1");
+        assert!(metrics.recent_logs[0].unmatched);
+        assert_eq!(metrics.recent_logs[0].status, 404);
+
+        // The default backend serves them instead, but they still count
+        let url = backend(Router::new().fallback(|| async { "default" })).await;
+        let (gateway, state) = gateway_with_state(json!({
+            "default_backend": url,
+            "routes": [{"path": "/api", "backend_url": "http://127.0.0.1:9"}]
+        }));
+        let response = send(
+            &gateway,
+            Request::get("/missing").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.metrics_store.get_metrics().unmatched_requests, 1);
+    }
+
+    #[tokio::test]
+    async fn test_bodies_are_streamed() {
+        // Tells the test as soon as the first chunk of an upload arrives,
+        // and sends the second half of a download only once allowed to
+        let (first_tx, mut first_rx) = mpsc::channel(1);
+        let release = Arc::new(Notify::new());
+        let release_download = release.clone();
+        let url = backend(
+            Router::new()
+                .route(
+                    "/upload",
+                    post(move |body: Body| async move {
+                        let mut stream = body.into_data_stream();
+                        let first = stream.next().await.unwrap().unwrap();
+                        first_tx.send(first).await.unwrap();
+                        let mut total = 0;
+                        while let Some(chunk) = stream.next().await {
+                            total += chunk.unwrap().len();
+                        }
+                        total.to_string()
+                    }),
+                )
+                .route(
+                    "/download",
+                    axum::routing::get(move || async move {
+                        let stream = futures_util::stream::unfold(0, move |sent| {
+                            let release = release_download.clone();
+                            async move {
+                                match sent {
+                                    0 => {}
+                                    1 => release.notified().await,
+                                    _ => return None,
+                                }
+                                Some((Ok::<_, io::Error>(Bytes::from("chunk ")), sent + 1))
+                            }
+                        });
+                        Body::from_stream(stream)
+                    }),
+                ),
+        )
+        .await;
+        // A response size limit mustn't hold back the download either
+        let gateway = gateway(json!({
+            "routes": [{"path": "/up", "backend_url": url, "max_response_body": 1024}]
+        }));
+
+        // The backend sees the first chunk while the rest is still unsent
+        let (tx, body) = channel_body();
+        let req = Request::post("/up/upload").body(body).unwrap();
+        let response = tokio::spawn({
+            let gateway = gateway.clone();
+            async move { send(&gateway, req).await }
+        });
+        tx.send("first ").await.unwrap();
+        let first = timeout(WAIT, first_rx.recv()).await.unwrap().unwrap();
+        assert_eq!(first, "first ");
+        tx.send("second").await.unwrap();
+        drop(tx);
+        let response = timeout(WAIT, response).await.unwrap().unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "6");
+
+        // The client gets the first chunk before the backend sends the rest
+        let req = Request::get("/up/download").body(Body::empty()).unwrap();
+        let mut body = send(&gateway, req).await.into_body();
+        let frame = timeout(WAIT, body.frame()).await.unwrap().unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "chunk ");
+        release.notify_one();
+        let rest = timeout(WAIT, body.collect()).await.unwrap().unwrap();
+        assert_eq!(rest.to_bytes(), "chunk ");
+    }
+
+    #[tokio::test]
+    async fn test_request_body_limit() {
+        let url = backend(Router::new().route(
+            "/upload",
+            post(|body: Body| async move {
+                let bytes = axum::body::to_bytes(body, usize::MAX).await;
+                bytes
+                    .map(|bytes| bytes.len().to_string())
+                    .unwrap_or_default()
+            }),
+        ))
+        .await;
+        let gateway = gateway(json!({
+            "routes": [{"path": "/up", "backend_url": url, "max_request_body": 8}]
+        }));
+
+        // Rejected up front by its Content-Length
+        let req = Request::post("/up/upload")
+            .header(CONTENT_LENGTH, 100)
+            .body(Body::from(vec![b'x'; 100]))
+            .unwrap();
+        assert_eq!(
+            send(&gateway, req).await.status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        // Without one, only once the streamed body passes the limit
+        let (tx, body) = channel_body();
+        let req = Request::post("/up/upload").body(body).unwrap();
+        let response = tokio::spawn({
+            let gateway = gateway.clone();
+            async move { send(&gateway, req).await }
+        });
+        tx.send("12345").await.unwrap();
+        tx.send("67890").await.unwrap();
+        drop(tx);
+        let response = timeout(WAIT, response).await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // A body within the limit still gets through
+        let (tx, body) = channel_body();
+        let req = Request::post("/up/upload").body(body).unwrap();
+        let response = tokio::spawn({
+            let gateway = gateway.clone();
+            async move { send(&gateway, req).await }
+        });
+        tx.send("1234").await.unwrap();
+        drop(tx);
+        let response = timeout(WAIT, response).await.unwrap().unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "4");
+    }
+
+    #[tokio::test]
+    async fn test_chunked_response_limit() {
+        fn chunked(chunks: usize) -> Body {
+            Body::from_stream(futures_util::stream::iter(
+                (0..chunks).map(|_| Ok::<_, io::Error>(Bytes::from_static(b"12345678"))),
+            ))
+        }
+        let url = backend(
+            Router::new()
+                .route("/small", get(|| async { chunked(2) }))
+                .route("/large", get(|| async { chunked(3) }))
+                .route(
+                    "/events",
+                    get(|| async { ([(CONTENT_TYPE, "text/event-stream")], chunked(3)) }),
+                ),
+        )
+        .await;
+        let (gateway, state) = gateway_with_state(json!({
+            "routes": [{"path": "/down", "backend_url": url, "max_response_body": 16}]
+        }));
+        let last_status = || state.metrics_store.get_metrics().recent_logs[0].status;
+
+        let response = send(
+            &gateway,
+            Request::get("/down/small").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "1234567812345678");
+
+        // Bodies are passed on as they arrive, so one that turns out too
+        // large is cut off, but still logged as failed
+        let response = send(
+            &gateway,
+            Request::get("/down/large").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(first, "12345678");
+        assert!(body.collect().await.is_err());
+        assert_eq!(last_status(), 502);
+
+        let response = send(
+            &gateway,
+            Request::get("/down/events").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.into_body().collect().await.is_err());
+        assert_eq!(last_status(), 502);
+    }
+
+    #[tokio::test]
+    async fn test_slow_backend_times_out() {
+        let url = backend(Router::new().route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                "too late"
+            }),
+        ))
+        .await;
+        let (gateway, state) = gateway_with_state(json!({
+            "routes": [{"path": "/api", "backend_url": url, "timeouts": {"read_idle_ms": 100}}]
+        }));
+
+        let response = send(
+            &gateway,
+            Request::get("/api/slow").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.starts_with(b"Backend timeout: "));
+        let metrics = state.metrics_store.get_metrics();
+        assert_eq!(metrics.total_timeouts, 1);
+        assert_eq!(metrics.recent_logs[0].status, 504);
+    }
+
+    #[tokio::test]
+    async fn test_total_timeout_covers_retries() {
+        let url = backend(Router::new().route(
+            "/busy",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                StatusCode::SERVICE_UNAVAILABLE
+            }),
+        ))
+        .await;
+        let route = |path: &str, backoff_base_ms: u64| {
+            json!({
+                "path": path,
+                "backend_url": url,
+                "timeouts": {"total_ms": 500},
+                "retry": {
+                    "attempts": 5,
+                    "on_status": [503],
+                    "backoff_base_ms": backoff_base_ms,
+                    "jitter": false
+                }
+            })
+        };
+        let (gateway, state) = gateway_with_state(json!({
+            "routes": [route("/quick", 10), route("/slow", 1000)]
+        }));
+        let last_log = || state.metrics_store.get_metrics().recent_logs[0].clone();
+
+        // The second attempt only gets what's left of the 500ms
+        let started = Instant::now();
+        let response = send(
+            &gateway,
+            Request::get("/quick/busy").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_millis(900));
+        assert_eq!(last_log().attempts.len(), 2);
+
+        // No retry is started when the backoff alone would outlast it
+        let response = send(
+            &gateway,
+            Request::get("/slow/busy").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(last_log().attempts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_breaker() {
+        let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let backend_hits = hits.clone();
+        let url = backend(Router::new().fallback(move || async move {
+            backend_hits.fetch_add(1, Ordering::Relaxed);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }))
+        .await;
+        // Both routes share the upstream, and with it its breaker state
+        let gateway = gateway(json!({
+            "routes": [
+                {"path": "/guarded", "backend_url": url,
+                 "circuit_breaker": {"min_requests": 2, "failure_rate_percent": 50, "open_ms": 60000}},
+                {"path": "/open", "backend_url": url}
+            ]
+        }));
+        let get_status = |path: &'static str| {
+            let gateway = gateway.clone();
+            async move {
+                let req = Request::get(path).body(Body::empty()).unwrap();
+                send(&gateway, req).await.status()
+            }
+        };
+
+        for _ in 0..2 {
+            assert_eq!(
+                get_status("/guarded").await,
+                StatusCode::INTERNAL_SERVER_ERROR
+            );
+        }
+        // Open now, so answered without trying the upstream
+        assert_eq!(
+            get_status("/guarded").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+
+        // A route without a breaker still reaches the upstream
+        assert_eq!(get_status("/open").await, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(hits.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_h2c_trailers_pass_through() {
+        // Echoes the request body, with the request's trailers and a
+        // grpc-status as the response's trailers
+        let url = backend(Router::new().route(
+            "/rpc/echo",
+            post(|body: Body| async move {
+                let collected = body.collect().await.unwrap();
+                let mut trailers = collected.trailers().cloned().unwrap_or_default();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                let frames = [Frame::data(collected.to_bytes()), Frame::trailers(trailers)];
+                let stream = futures_util::stream::iter(frames.map(Ok::<_, io::Error>));
+                (
+                    [(CONTENT_TYPE, "application/grpc")],
+                    Body::new(StreamBody::new(stream)),
+                )
+            }),
+        ))
+        .await;
+        let addr = serve_gateway(gateway(json!({
+            "routes": [{"path": "/rpc", "backend_url": url, "grpc": true}]
+        })))
+        .await;
+
+        // The client speaks HTTP/2 without an upgrade, as gRPC clients do
+        let client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", HeaderValue::from_static("abc"));
+        let frames = [Frame::data(Bytes::from("ping")), Frame::trailers(trailers)];
+        let stream = futures_util::stream::iter(frames.map(Ok::<_, io::Error>));
+        let response = client
+            .post(format!("http://{}/rpc/echo", addr))
+            .header(CONTENT_TYPE, "application/grpc")
+            .header(TE, "trailers")
+            .body(reqwest::Body::wrap(StreamBody::new(stream)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.version(), http::Version::HTTP_2);
+        let collected = timeout(WAIT, http::Response::from(response).into_body().collect())
+            .await
+            .unwrap()
+            .unwrap();
+        let trailers = collected.trailers().cloned().unwrap();
+        assert_eq!(trailers["x-checksum"], "abc");
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(collected.to_bytes(), "ping");
+    }
+
+    #[tokio::test]
+    async fn test_hop_by_hop_response_headers_are_stripped() {
+        // A streamed body goes out chunked, with a Transfer-Encoding header
+        let url = backend(Router::new().route(
+            "/hops",
+            get(|| async {
+                let chunks = futures_util::stream::iter(["a", "b"].map(Ok::<_, io::Error>));
+                (
+                    [
+                        (CONNECTION, "x-session"),
+                        (HeaderName::from_static("x-session"), "abc"),
+                        (HeaderName::from_static("keep-alive"), "timeout=5"),
+                        (HeaderName::from_static("x-kept"), "1"),
+                    ],
+                    Body::from_stream(chunks),
+                )
+            }),
+        ))
+        .await;
+        let gateway = gateway(json!({
+            "routes": [{"path": "/api", "backend_url": url}]
+        }));
+
+        let response = send(
+            &gateway,
+            Request::get("/api/hops").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        for name in ["connection", "x-session", "keep-alive", "transfer-encoding"] {
+            assert!(!headers.contains_key(name), "{} was passed on", name);
+        }
+        assert_eq!(headers["x-kept"], "1");
+        assert!(headers.contains_key(VIA));
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "ab");
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_through_proxy() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::{connect_async, tungstenite::Error, tungstenite::Message};
+
+        // Echoes text messages back until the client closes
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    while let Some(Ok(message)) = ws.next().await {
+                        if message.is_text() && ws.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        // Nothing listens here once the listener is dropped
+        let refused = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let (gateway, state) = gateway_with_state(json!({
+            "routes": [
+                {"path": "/echo", "backend_url": echo},
+                {"path": "/down", "backend_url": refused,
+                 "circuit_breaker": {"min_requests": 1, "failure_rate_percent": 100, "open_ms": 60000}}
+            ]
+        }));
+        let addr = serve_gateway(gateway).await;
+
+        let (mut ws, response) = timeout(WAIT, connect_async(format!("ws://{}/echo", addr)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        ws.send(Message::text("hello")).await.unwrap();
+        let echoed = timeout(WAIT, ws.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(echoed, Message::text("hello"));
+        ws.close(None).await.unwrap();
+
+        let upgrade_status = |path: &'static str| async move {
+            match timeout(WAIT, connect_async(format!("ws://{}{}", addr, path)))
+                .await
+                .unwrap()
+            {
+                Err(Error::Http(response)) => response.status(),
+                other => panic!("expected an HTTP error, got {:?}", other.map(|r| r.1)),
+            }
+        };
+        // The failed handshake opens the upstream's breaker, so the next
+        // upgrade is turned away without trying it
+        assert_eq!(upgrade_status("/down").await, StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            upgrade_status("/down").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        let logs = state.metrics_store.get_metrics().recent_logs;
+        assert_eq!(logs[0].status, 503);
+        assert_eq!(logs[1].status, 502);
+    }
+
+    #[test]
+    fn test_is_event_stream() {
+        let headers = |content_type: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            headers
+        };
+        assert!(is_event_stream(&headers("text/event-stream")));
+        assert!(is_event_stream(&headers(
+            "Text/Event-Stream ; charset=utf-8"
+        )));
+        assert!(!is_event_stream(&headers("application/json")));
+        assert!(!is_event_stream(&headers("text/event-stream-ish")));
+        assert!(!is_event_stream(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_pending_log() {
+        let (metrics, _rx) = MetricsStore::new();
+        let metrics = Arc::new(metrics);
+        let pending = |failure: Option<BodyFailure>| PendingLog {
+            log: Some(RequestLog::new(
+                "POST".to_string(),
+                "/rpc".to_string(),
+                200,
+                chrono::Duration::zero(),
+                "127.0.0.1".to_string(),
+            )),
+            start_time: Utc::now(),
+            failure,
+            metrics: metrics.clone(),
+        };
+        let last_log = || metrics.get_metrics().recent_logs[0].clone();
+
+        // Logged once dropped, with the status from the trailers
+        let mut log = pending(Some(BodyFailure::default()));
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("5"));
+        log.set_grpc_status(&trailers);
+        assert!(metrics.get_metrics().recent_logs.is_empty());
+        drop(log);
+        assert_eq!(last_log().status, 200);
+        assert_eq!(last_log().grpc_status, Some(5));
+
+        // A body cut off part way through is logged with the failure's
+        // status
+        let failure = BodyFailure::default();
+        let log = pending(Some(failure.clone()));
+        let _ = failure.0.set(ProxyError::Timeout("no data".to_string()));
+        drop(log);
+        assert_eq!(last_log().status, 504);
+        assert_eq!(metrics.get_metrics().total_requests, 2);
+    }
+
+    #[tokio::test]
+    async fn test_stream_timeouts_follow_response() {
+        // Sends one chunk, then another after `gap`
+        fn gappy(gap: Duration) -> Body {
+            Body::from_stream(futures_util::stream::unfold(0, move |sent| async move {
+                match sent {
+                    0 => {}
+                    1 => tokio::time::sleep(gap).await,
+                    _ => return None,
+                }
+                Some((Ok::<_, io::Error>(Bytes::from("data: x\n\n")), sent + 1))
+            }))
+        }
+        let gap = Duration::from_millis(400);
+        let url = backend(
+            Router::new()
+                .route(
+                    "/events",
+                    get(move || async move { ([(CONTENT_TYPE, "text/event-stream")], gappy(gap)) }),
+                )
+                .route("/plain", get(move || async move { gappy(gap) })),
+        )
+        .await;
+        let route = |path: &str, timeouts: serde_json::Value| json!({"path": path, "backend_url": url, "timeouts": timeouts});
+        let (gateway, state) = gateway_with_state(json!({
+            "routes": [
+                route("/total", json!({"total_ms": 200})),
+                route("/idle", json!({"stream_idle_ms": 200})),
+            ]
+        }));
+        let get_body = |path: &'static str, accept: Option<&'static str>| {
+            let gateway = gateway.clone();
+            async move {
+                let mut req = Request::get(path);
+                if let Some(accept) = accept {
+                    req = req.header(ACCEPT, accept);
+                }
+                let response = send(&gateway, req.body(Body::empty()).unwrap()).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                timeout(WAIT, response.into_body().collect()).await.unwrap()
+            }
+        };
+        let metrics = || state.metrics_store.get_metrics();
+
+        // An event stream outlives `total_ms` without the client asking for
+        // one
+        let body = get_body("/total/events", None).await.unwrap().to_bytes();
+        assert_eq!(body, "data: x\n\ndata: x\n\n");
+        assert_eq!(metrics().recent_logs[0].status, 200);
+
+        // Asking for one doesn't lift `total_ms` from anything else
+        assert!(
+            get_body("/total/plain", Some("text/event-stream"))
+                .await
+                .is_err()
+        );
+        assert_eq!(metrics().total_timeouts, 1);
+
+        // An event stream that goes quiet is cut off, and logged as timed out
+        assert!(get_body("/idle/events", None).await.is_err());
+        assert_eq!(metrics().recent_logs[0].status, 504);
+        assert_eq!(metrics().total_timeouts, 2);
+    }
+}
@@ -0,0 +1,178 @@
+use std::{ffi::OsString, path::PathBuf, sync::Arc, time::Duration};
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::{
+    models::{ReloadEvent, ReloadTrigger, WsMessage},
+    state::AppState,
+};
+
+/// Editors tend to save in several steps (truncate, write, rename), so wait
+/// for the burst of events to settle before reading the file.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Reloads the config, then reports the outcome to the logs and the `/ws` feed.
+pub fn reload(state: &AppState, trigger: ReloadTrigger) {
+    let result = state.reload_config().map_err(|e| e.to_string());
+    report(state, trigger, result);
+}
+
+/// Logs a change to the routing table and broadcasts it on the `/ws` feed.
+pub fn report(state: &AppState, trigger: ReloadTrigger, result: Result<usize, String>) {
+    match &result {
+        Ok(routes) => info!(
+            "Reloaded {} ({:?}): {} routes active",
+            state.config_path.display(),
+            trigger,
+            routes
+        ),
+        Err(e) => error!(
+            "Rejected reload of {} ({:?}), keeping previous routes: {}",
+            state.config_path.display(),
+            trigger,
+            e
+        ),
+    }
+
+    let event = ReloadEvent::new(trigger, &result);
+    let _ = state
+        .metrics_store
+        .get_broadcaster()
+        .send(WsMessage::ConfigReload { event });
+}
+
+/// Starts reloading the config when the file changes or on SIGHUP.
+pub fn spawn_watchers(state: Arc<AppState>) {
+    spawn_file_watcher(state.clone());
+    #[cfg(unix)]
+    spawn_sighup_handler(state);
+}
+
+fn spawn_file_watcher(state: Arc<AppState>) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |res: notify::Result<Event>| {
+        let _ = tx.send(res);
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Couldn't start config file watcher: {}", e);
+            return;
+        }
+    };
+
+    // Watch the directory rather than the file so that editors which replace
+    // the file through a rename are still picked up.
+    let watch_dir = match state.config_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    if let Err(e) = watcher.watch(&watch_dir, RecursiveMode::NonRecursive) {
+        warn!("Couldn't watch {}: {}", watch_dir.display(), e);
+        return;
+    }
+    let file_name = state.config_path.file_name().map(OsString::from);
+
+    tokio::spawn(async move {
+        let _watcher = watcher;
+        while let Some(res) = rx.recv().await {
+            match res {
+                Ok(event) if is_config_change(&event, file_name.as_ref()) => {}
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("Config file watcher error: {}", e);
+                    continue;
+                }
+            }
+
+            tokio::time::sleep(DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+
+            reload(&state, ReloadTrigger::FileChange);
+        }
+    });
+}
+
+fn is_config_change(event: &Event, file_name: Option<&OsString>) -> bool {
+    if matches!(event.kind, EventKind::Access(_)) {
+        return false;
+    }
+    event
+        .paths
+        .iter()
+        .any(|path| path.file_name() == file_name.map(|n| n.as_os_str()))
+}
+
+#[cfg(unix)]
+fn spawn_sighup_handler(state: Arc<AppState>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Couldn't install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            reload(&state, ReloadTrigger::Sighup);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load_config;
+    use notify::event::{AccessKind, CreateKind, ModifyKind};
+    use std::fs;
+
+    #[test]
+    fn test_config_change_filter() {
+        let file_name = Some(OsString::from("config.json"));
+        let event = |kind, path: &str| Event::new(kind).add_path(PathBuf::from(path));
+
+        let modified = event(EventKind::Modify(ModifyKind::Any), "/etc/gw/config.json");
+        assert!(is_config_change(&modified, file_name.as_ref()));
+        let renamed_in = event(EventKind::Create(CreateKind::File), "/etc/gw/config.json");
+        assert!(is_config_change(&renamed_in, file_name.as_ref()));
+
+        let read = event(EventKind::Access(AccessKind::Any), "/etc/gw/config.json");
+        assert!(!is_config_change(&read, file_name.as_ref()));
+        let other = event(
+            EventKind::Modify(ModifyKind::Any),
+            "/etc/gw/config.json.swp",
+        );
+        assert!(!is_config_change(&other, file_name.as_ref()));
+    }
+
+    #[test]
+    fn test_rejected_reload_keeps_routes() {
+        let path =
+            std::env::temp_dir().join(format!("gateway-reload-{:08x}.json", rand::random::<u32>()));
+        fs::write(
+            &path,
+            r#"{"routes": [{"path": "/api", "backend_url": "http://api.internal"}]}"#,
+        )
+        .unwrap();
+        let state = AppState::new(path.clone(), load_config(&path).unwrap());
+        let mut events = state.metrics_store.get_broadcaster().subscribe();
+
+        fs::write(&path, r#"{"routes": [{"path": "api"}]}"#).unwrap();
+        reload(&state, ReloadTrigger::FileChange);
+        fs::remove_file(&path).unwrap();
+
+        let routes = state.routes.load();
+        assert_eq!(routes.routes().len(), 1);
+        assert_eq!(routes.routes()[0].backend_url, "http://api.internal");
+        let Ok(WsMessage::ConfigReload { event }) = events.try_recv() else {
+            panic!("expected a ConfigReload message");
+        };
+        assert!(!event.success);
+        assert_eq!(event.routes, None);
+        assert!(event.error.unwrap().contains("backend_url"));
+    }
+}
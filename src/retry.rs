@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::config::{RetryConfig, RetryOn};
+
+/// Length of the window retry budgets are counted over
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Per-route counts of recent requests and retries, which cap retries at
+/// `budget_percent` of the requests so retries can't multiply the load on a
+/// backend that is already failing
+#[derive(Default)]
+pub struct RetryBudgets {
+    routes: Mutex<HashMap<String, Budget>>,
+}
+
+struct Budget {
+    window_start: Instant,
+    requests: u64,
+    retries: u64,
+}
+
+impl RetryBudgets {
+    pub fn record_request(&self, route: &str) {
+        let mut routes = self.routes.lock().unwrap();
+        let budget = routes.entry(route.to_string()).or_insert_with(Budget::new);
+        budget.roll(Instant::now());
+        budget.requests += 1;
+    }
+
+    /// Takes one retry from the route's budget, returning `false` when it
+    /// is spent
+    pub fn try_retry(&self, route: &str, policy: &RetryConfig) -> bool {
+        let mut routes = self.routes.lock().unwrap();
+        let budget = routes.entry(route.to_string()).or_insert_with(Budget::new);
+        budget.roll(Instant::now());
+
+        let floor = u64::from(policy.min_retries_per_second) * BUDGET_WINDOW.as_secs();
+        let allowed = (budget.requests * u64::from(policy.budget_percent) / 100).max(floor);
+        if budget.retries >= allowed {
+            return false;
+        }
+        budget.retries += 1;
+        true
+    }
+}
+
+impl Budget {
+    fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            requests: 0,
+            retries: 0,
+        }
+    }
+
+    fn roll(&mut self, now: Instant) {
+        if now.duration_since(self.window_start) >= BUDGET_WINDOW {
+            *self = Self {
+                window_start: now,
+                requests: 0,
+                retries: 0,
+            };
+        }
+    }
+}
+
+/// Delay before retry number `retry` (starting at 1): exponential backoff
+/// capped at `backoff_max_ms`, with full jitter unless the policy turns it
+/// off
+pub fn backoff(policy: &RetryConfig, retry: u32) -> Duration {
+    let exponent = retry.saturating_sub(1).min(32);
+    let ceiling = policy
+        .backoff_base_ms
+        .saturating_mul(1 << exponent)
+        .min(policy.backoff_max_ms);
+    if !policy.jitter {
+        return Duration::from_millis(ceiling);
+    }
+    Duration::from_millis(rand::rng().random_range(0..=ceiling))
+}
+
+/// Which kind of failure a request error was, for matching `on_errors`
+pub fn error_kind(e: &reqwest::Error) -> RetryOn {
+    if e.is_timeout() {
+        RetryOn::Timeout
+    } else if e.is_connect() {
+        RetryOn::Connect
+    } else {
+        RetryOn::Reset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryConfig {
+        serde_json::from_str(r#"{"budget_percent": 50, "min_retries_per_second": 0}"#).unwrap()
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = policy();
+        for retry in 1..40 {
+            let delay = backoff(&policy, retry);
+            assert!(delay <= Duration::from_millis(policy.backoff_max_ms));
+        }
+        assert!(backoff(&policy, 1) <= Duration::from_millis(policy.backoff_base_ms));
+
+        let policy = RetryConfig {
+            jitter: false,
+            ..policy
+        };
+        assert_eq!(backoff(&policy, 1), Duration::from_millis(25));
+        assert_eq!(backoff(&policy, 3), Duration::from_millis(100));
+        assert_eq!(backoff(&policy, 10), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_budget_limits_retries() {
+        let (budgets, policy) = (RetryBudgets::default(), policy());
+        assert!(!budgets.try_retry("api", &policy));
+
+        for _ in 0..4 {
+            budgets.record_request("api");
+        }
+        assert!(budgets.try_retry("api", &policy));
+        assert!(budgets.try_retry("api", &policy));
+        assert!(!budgets.try_retry("api", &policy));
+        assert!(!budgets.try_retry("other", &policy));
+    }
+}
@@ -1,36 +1,704 @@
-use crate::state::Route;
+use std::{cmp::Reverse, collections::HashMap};
 
-pub fn match_route<'a>(routes: &'a Vec<Route>, path: &str) -> Option<&'a Route> {
-    routes.iter().find(|route| {
-        if route.path == "/" {
-            return path.starts_with('/');
+use http::{HeaderMap, HeaderName, Method, Request, header::HOST};
+use ipnet::IpNet;
+use regex::Regex;
+use tracing::warn;
+
+use crate::{
+    balancer::Balancer,
+    config::{GatewayConfig, Rewrite, Route, ValueMatch},
+    forwarded::parse_proxy,
+    header_rules::{CompiledHeaderRules, HeaderVars},
+    template::{Template, is_identifier},
+};
+
+/// Routing table built from one config load. The whole table is swapped
+/// on reload so a request never sees a mix of old and new routes.
+pub struct RouteTable {
+    config: GatewayConfig,
+    /// Trees for routes with an exact `host`
+    hosts: HashMap<String, Node>,
+    /// Trees for `*.suffix` hosts, keyed by `.suffix`, longest suffix first
+    wildcard_hosts: Vec<(String, Node)>,
+    /// Tree for routes without a `host`, used for every request
+    any_host: Node,
+    /// Compiled form of each route. `None` if the route failed to compile,
+    /// in which case it never matches.
+    compiled: Vec<Option<CompiledRoute>>,
+    /// Catch-all route for `default_backend`
+    default_route: Option<(Route, CompiledRoute)>,
+    /// Parsed `forwarded.trusted_proxies`
+    trusted_proxies: Vec<IpNet>,
+}
+
+/// One path segment in the prefix tree. A route is stored on the node where
+/// its path ends, so every route stored along the walk for a request path
+/// is a prefix match for it.
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    /// Child for a `{param}` segment, which matches any non-empty segment
+    param: Option<Box<Node>>,
+    /// Indexes into the config's routes
+    routes: Vec<usize>,
+}
+
+/// A route path split into segments, e.g. `/users/{id}/files/{*rest}`
+#[derive(Debug)]
+pub struct PathPattern {
+    segments: Vec<Segment>,
+    /// Name of a trailing `{*name}` segment, which captures the rest of the
+    /// path
+    catch_all: Option<String>,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Static(String),
+    Param(String),
+}
+
+struct CompiledRoute {
+    pattern: PathPattern,
+    predicates: Predicates,
+    rewrite: CompiledRewrite,
+    request_headers: CompiledHeaderRules,
+    response_headers: CompiledHeaderRules,
+    balancer: Balancer,
+}
+
+/// A route's request predicates, all of which must hold
+struct Predicates {
+    methods: Vec<Method>,
+    headers: Vec<(HeaderName, Matcher)>,
+    query: Vec<(String, Matcher)>,
+}
+
+enum CompiledRewrite {
+    StripPrefix,
+    Preserve,
+    ReplacePrefix(String),
+    Template(Template),
+    Regex(Regex, String),
+}
+
+enum Matcher {
+    Present,
+    Exact(String),
+    Regex(Regex),
+}
+
+/// The route picked for a request, with the values captured from its path
+pub struct RouteMatch<'a> {
+    pub route: &'a Route,
+    compiled: &'a CompiledRoute,
+    /// Raw (still percent-encoded) values of the path's `{name}` segments
+    /// and `{*name}` catch-all
+    pub params: Vec<(String, String)>,
+    /// The request path
+    pub path: String,
+    /// Rest of the request path after the part the route matched, e.g.
+    /// `/123` for `/api/123` on route `/api`
+    pub remainder: String,
+}
+
+/// A prefix-matching candidate found while walking the tree
+struct Candidate {
+    index: usize,
+    /// Number of request path segments the route consumed
+    depth: usize,
+    /// How many of those were literal segments rather than `{param}`s
+    static_depth: usize,
+}
+
+impl Node {
+    fn insert(&mut self, pattern: &PathPattern, index: usize) {
+        let node = pattern
+            .segments
+            .iter()
+            .fold(self, |node, segment| match segment {
+                Segment::Static(name) => node.children.entry(name.clone()).or_default(),
+                Segment::Param(_) => node.param.get_or_insert_default(),
+            });
+        node.routes.push(index);
+    }
+
+    fn collect(
+        &self,
+        segments: &[&str],
+        depth: usize,
+        static_depth: usize,
+        out: &mut Vec<Candidate>,
+    ) {
+        out.extend(self.routes.iter().map(|&index| Candidate {
+            index,
+            depth,
+            static_depth,
+        }));
+
+        let Some((first, rest)) = segments.split_first() else {
+            return;
+        };
+        if let Some(child) = self.children.get(*first) {
+            child.collect(rest, depth + 1, static_depth + 1, out);
+        }
+        if let Some(child) = &self.param
+            && !first.is_empty()
+        {
+            child.collect(rest, depth + 1, static_depth, out);
+        }
+    }
+}
+
+impl RouteTable {
+    pub fn new(config: GatewayConfig) -> Self {
+        for (shadowed, by) in shadowed_routes(&config.routes) {
+            let (shadowed, by) = (&config.routes[shadowed], &config.routes[by]);
+            warn!(
+                "Route `{}` ({}) is unreachable: every path it matches goes to route `{}` ({}) which has a higher priority",
+                shadowed.id, shadowed.path, by.id, by.path
+            );
+        }
+
+        let compiled: Vec<Option<CompiledRoute>> = config
+            .routes
+            .iter()
+            .map(|route| {
+                let compiled = CompiledRoute::compile(route);
+                if compiled.is_none() {
+                    warn!("Route `{}` is invalid and will never match", route.id);
+                }
+                compiled
+            })
+            .collect();
+
+        let mut hosts: HashMap<String, Node> = HashMap::new();
+        let mut wildcard_hosts: HashMap<String, Node> = HashMap::new();
+        let mut any_host = Node::default();
+        for (index, route) in config.routes.iter().enumerate() {
+            let Some(compiled) = &compiled[index] else {
+                continue;
+            };
+            let tree = match route.host.as_deref().map(str::to_ascii_lowercase) {
+                Some(host) => match host.strip_prefix('*') {
+                    Some(suffix) => wildcard_hosts.entry(suffix.to_string()).or_default(),
+                    None => hosts.entry(host).or_default(),
+                },
+                None => &mut any_host,
+            };
+            tree.insert(&compiled.pattern, index);
+        }
+
+        let mut wildcard_hosts: Vec<(String, Node)> = wildcard_hosts.into_iter().collect();
+        wildcard_hosts.sort_by_key(|(suffix, _)| Reverse(suffix.len()));
+
+        let default_route = config.default_backend.as_ref().and_then(|url| {
+            let route = Route {
+                id: "default".to_string(),
+                path: "/".to_string(),
+                backend_url: url.clone(),
+                rewrite: Some(Rewrite::Preserve),
+                ..Default::default()
+            };
+            let compiled = CompiledRoute::compile(&route)?;
+            Some((route, compiled))
+        });
+
+        let trusted_proxies = config
+            .forwarded
+            .trusted_proxies
+            .iter()
+            .filter_map(|proxy| parse_proxy(proxy))
+            .collect();
+
+        Self {
+            config,
+            hosts,
+            wildcard_hosts,
+            any_host,
+            compiled,
+            default_route,
+            trusted_proxies,
+        }
+    }
+
+    pub fn config(&self) -> &GatewayConfig {
+        &self.config
+    }
+
+    pub fn trusted_proxies(&self) -> &[IpNet] {
+        &self.trusted_proxies
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.config.routes
+    }
+
+    /// Picks the route for a request.
+    ///
+    /// Routes for the request's host are tried first: an exact `host`, else
+    /// the longest matching wildcard, else `server.default_host` when the
+    /// host is unknown. Routes without a `host` are the fallback. Within
+    /// each group the candidates whose path is a prefix of the request path
+    /// are ranked by highest `priority`, then longest (most specific) path,
+    /// then most literal segments, then most predicates, then declaration
+    /// order, and the first whose method, header and query predicates all
+    /// hold is picked.
+    pub fn match_route<B>(&self, req: &Request<B>) -> Option<RouteMatch<'_>> {
+        let path = req.uri().path();
+        let host = req
+            .uri()
+            .authority()
+            .map(|authority| authority.host())
+            .or_else(|| req.headers().get(HOST).and_then(|v| v.to_str().ok()))
+            .map(normalize_host);
+
+        let host_tree = host
+            .as_deref()
+            .and_then(|host| self.host_tree(host))
+            .or_else(|| {
+                let default_host = self.config.server.default_host.as_deref()?;
+                self.host_tree(&default_host.to_ascii_lowercase())
+            });
+
+        let request_segments: Vec<&str> = segments(path).collect();
+        let candidate = host_tree
+            .and_then(|tree| self.lookup(tree, &request_segments, req))
+            .or_else(|| self.lookup(&self.any_host, &request_segments, req))?;
+
+        let compiled = self.compiled[candidate.index].as_ref()?;
+        Some(RouteMatch {
+            route: &self.config.routes[candidate.index],
+            params: compiled
+                .pattern
+                .captures(&request_segments, path, candidate.depth),
+            path: path.to_string(),
+            remainder: remainder(path, candidate.depth).to_string(),
+            compiled,
+        })
+    }
+
+    /// Match for `default_backend`, to use when `match_route` finds nothing.
+    /// `None` if no default backend is configured.
+    pub fn default_match(&self, path: &str) -> Option<RouteMatch<'_>> {
+        let (route, compiled) = self.default_route.as_ref()?;
+        Some(RouteMatch {
+            route,
+            compiled,
+            params: Vec::new(),
+            path: path.to_string(),
+            remainder: path.to_string(),
+        })
+    }
+
+    fn host_tree(&self, host: &str) -> Option<&Node> {
+        self.hosts.get(host).or_else(|| {
+            self.wildcard_hosts
+                .iter()
+                .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+                .map(|(_, tree)| tree)
+        })
+    }
+
+    fn lookup<B>(&self, tree: &Node, segments: &[&str], req: &Request<B>) -> Option<Candidate> {
+        let mut candidates = Vec::new();
+        tree.collect(segments, 0, 0, &mut candidates);
+
+        candidates.sort_by_key(|candidate| {
+            let route = &self.config.routes[candidate.index];
+            (
+                Reverse(route.priority()),
+                Reverse(candidate.depth),
+                Reverse(candidate.static_depth),
+                Reverse(route.predicate_count()),
+                candidate.index,
+            )
+        });
+        candidates.into_iter().find(|candidate| {
+            self.compiled[candidate.index]
+                .as_ref()
+                .is_some_and(|compiled| compiled.predicates.matches(req))
+        })
+    }
+}
+
+impl RouteMatch<'_> {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Path to request from the backend, according to the route's
+    /// `rewrite` mode
+    pub fn upstream_path(&self) -> String {
+        match &self.compiled.rewrite {
+            CompiledRewrite::StripPrefix => self.remainder.clone(),
+            CompiledRewrite::Preserve => self.path.clone(),
+            CompiledRewrite::ReplacePrefix(prefix) => format!("{}{}", prefix, self.remainder),
+            CompiledRewrite::Template(template) => {
+                let mut path = template.render(|name| self.param(name));
+                // Without a `{*name}` catch-all the template only replaces
+                // the part of the path the route matched
+                if self.compiled.pattern.catch_all.is_none() {
+                    path.push_str(&self.remainder);
+                }
+                path
+            }
+            CompiledRewrite::Regex(regex, replacement) => {
+                let path = regex.replace_all(&self.path, replacement.as_str());
+                // Parts of the path the pattern didn't match are kept, so
+                // the result can still lack a leading '/'. Joined straight
+                // onto the upstream URL, it would change the host.
+                if path.starts_with('/') {
+                    path.into_owned()
+                } else {
+                    format!("/{}", path)
+                }
+            }
         }
-        if path.starts_with(&route.path) {
-            let remaining = &path[route.path.len()..];
-            remaining.is_empty() || remaining.starts_with('/') || remaining.starts_with('?')
-        } else {
-            false
+    }
+
+    /// Full URI for the request on one of the route's upstreams
+    pub fn backend_uri(&self, upstream: &str, query: Option<&str>) -> String {
+        let query = query.map(|q| format!("?{}", q)).unwrap_or_default();
+        format!("{}{}{}", upstream, self.upstream_path(), query)
+    }
+
+    pub fn balancer(&self) -> &Balancer {
+        &self.compiled.balancer
+    }
+
+    /// Applies the route's `request_headers` rules to the headers sent
+    /// upstream
+    pub fn rewrite_request_headers(&self, headers: &mut HeaderMap, vars: &HeaderVars) {
+        self.compiled
+            .request_headers
+            .apply(headers, |name| self.param(name).or_else(|| vars.get(name)));
+    }
+
+    /// Applies the route's `response_headers` rules to the headers sent
+    /// back to the client
+    pub fn rewrite_response_headers(&self, headers: &mut HeaderMap, vars: &HeaderVars) {
+        self.compiled
+            .response_headers
+            .apply(headers, |name| self.param(name).or_else(|| vars.get(name)));
+    }
+}
+
+impl CompiledRoute {
+    fn compile(route: &Route) -> Option<Self> {
+        let rewrite = match &route.rewrite {
+            None if route.grpc => CompiledRewrite::Preserve,
+            None | Some(Rewrite::StripPrefix) => CompiledRewrite::StripPrefix,
+            Some(Rewrite::Preserve) => CompiledRewrite::Preserve,
+            Some(Rewrite::ReplacePrefix { prefix }) => {
+                CompiledRewrite::ReplacePrefix(prefix.clone())
+            }
+            Some(Rewrite::Template { path }) => {
+                CompiledRewrite::Template(Template::parse(path).ok()?)
+            }
+            Some(Rewrite::Regex {
+                pattern,
+                replacement,
+            }) => CompiledRewrite::Regex(Regex::new(pattern).ok()?, replacement.clone()),
+        };
+
+        Some(Self {
+            pattern: PathPattern::parse(&route.path).ok()?,
+            predicates: Predicates::compile(route)?,
+            rewrite,
+            request_headers: CompiledHeaderRules::compile(&route.request_headers)?,
+            response_headers: CompiledHeaderRules::compile(&route.response_headers)?,
+            balancer: Balancer::new(route),
+        })
+    }
+}
+
+impl PathPattern {
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let mut pattern = PathPattern {
+            segments: Vec::new(),
+            catch_all: None,
+        };
+
+        for segment in segments(path) {
+            if pattern.catch_all.is_some() {
+                return Err("`{*name}` must be the last segment".to_string());
+            }
+            let param = segment
+                .strip_prefix('{')
+                .and_then(|segment| segment.strip_suffix('}'));
+            match param {
+                Some(name) => {
+                    let (name, catch_all) = match name.strip_prefix('*') {
+                        Some(name) => (name, true),
+                        None => (name, false),
+                    };
+                    if !is_identifier(name) {
+                        return Err(format!("`{}` is not a valid parameter", segment));
+                    }
+                    if pattern.captures_names().any(|existing| existing == name) {
+                        return Err(format!("parameter `{}` is used twice", name));
+                    }
+                    if catch_all {
+                        pattern.catch_all = Some(name.to_string());
+                    } else {
+                        pattern.segments.push(Segment::Param(name.to_string()));
+                    }
+                }
+                None if segment.contains(['{', '}']) => {
+                    return Err(format!(
+                        "`{}` must be a literal or a whole `{{name}}` segment",
+                        segment
+                    ));
+                }
+                None => pattern.segments.push(Segment::Static(segment.to_string())),
+            }
         }
-    })
+
+        Ok(pattern)
+    }
+
+    /// Names this pattern captures, including the catch-all
+    pub fn captures_names(&self) -> impl Iterator<Item = &str> {
+        self.segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Param(name) => Some(name.as_str()),
+                Segment::Static(_) => None,
+            })
+            .chain(self.catch_all.as_deref())
+    }
+
+    /// Canonical form in which equivalent patterns are equal, e.g. both
+    /// `/users/{id}` and `/users/{user_id}/{*rest}` become `/users/{}`
+    pub fn canonical(&self) -> String {
+        let mut canonical = String::new();
+        for segment in &self.segments {
+            canonical.push('/');
+            canonical.push_str(match segment {
+                Segment::Static(name) => name,
+                Segment::Param(_) => "{}",
+            });
+        }
+        if canonical.is_empty() {
+            canonical.push('/');
+        }
+        canonical
+    }
+
+    /// Whether every path this pattern is a prefix of, `other` is too
+    fn covers(&self, other: &PathPattern) -> bool {
+        self.segments.len() <= other.segments.len()
+            && self
+                .segments
+                .iter()
+                .zip(&other.segments)
+                .all(|(mine, theirs)| match (mine, theirs) {
+                    (Segment::Param(_), _) => true,
+                    (Segment::Static(a), Segment::Static(b)) => a == b,
+                    (Segment::Static(_), Segment::Param(_)) => false,
+                })
+    }
+
+    fn captures(
+        &self,
+        request_segments: &[&str],
+        path: &str,
+        depth: usize,
+    ) -> Vec<(String, String)> {
+        let mut params: Vec<(String, String)> = self
+            .segments
+            .iter()
+            .zip(request_segments)
+            .filter_map(|(segment, value)| match segment {
+                Segment::Param(name) => Some((name.clone(), value.to_string())),
+                Segment::Static(_) => None,
+            })
+            .collect();
+        if let Some(name) = &self.catch_all {
+            let rest = remainder(path, depth);
+            params.push((name.clone(), rest.trim_start_matches('/').to_string()));
+        }
+        params
+    }
+}
+
+impl Predicates {
+    fn compile(route: &Route) -> Option<Self> {
+        let methods = route
+            .methods
+            .iter()
+            .map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()).ok())
+            .collect::<Option<_>>()?;
+        let headers = route
+            .headers
+            .iter()
+            .map(|header| {
+                let name = HeaderName::from_bytes(header.name.as_bytes()).ok()?;
+                Some((name, Matcher::compile(header)?))
+            })
+            .collect::<Option<_>>()?;
+        let query = route
+            .query
+            .iter()
+            .map(|param| Some((param.name.clone(), Matcher::compile(param)?)))
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            methods,
+            headers,
+            query,
+        })
+    }
+
+    fn matches<B>(&self, req: &Request<B>) -> bool {
+        if !self.methods.is_empty() && !self.methods.contains(req.method()) {
+            return false;
+        }
+
+        let headers_match = self.headers.iter().all(|(name, matcher)| {
+            req.headers()
+                .get_all(name)
+                .iter()
+                .any(|value| value.to_str().is_ok_and(|value| matcher.matches(value)))
+        });
+        if !headers_match {
+            return false;
+        }
+
+        if self.query.is_empty() {
+            return true;
+        }
+        let params: Vec<(String, String)> =
+            form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect();
+        self.query.iter().all(|(name, matcher)| {
+            params
+                .iter()
+                .any(|(key, value)| key == name && matcher.matches(value))
+        })
+    }
+}
+
+impl Matcher {
+    fn compile(value_match: &ValueMatch) -> Option<Self> {
+        match (&value_match.value, &value_match.regex) {
+            (None, None) => Some(Matcher::Present),
+            (Some(value), None) => Some(Matcher::Exact(value.clone())),
+            (None, Some(regex)) => Regex::new(regex).ok().map(Matcher::Regex),
+            (Some(_), Some(_)) => None,
+        }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Matcher::Present => true,
+            Matcher::Exact(expected) => value == expected,
+            Matcher::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+/// Lowercases a Host header value and drops its port, keeping IPv6
+/// literals such as `[::1]` intact.
+fn normalize_host(host: &str) -> String {
+    let host = match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    };
+    host.to_ascii_lowercase()
+}
+
+/// Splits a path into the segments used as tree keys. `/` has none, and
+/// `/api/users` has `api` and `users`.
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    path.split('/').filter(move |_| !path.is_empty())
+}
+
+/// The part of `path` after its first `depth` segments, e.g. `/b/c` for
+/// `/a/b/c` at depth 1
+fn remainder(path: &str, depth: usize) -> &str {
+    let mut offset = 0;
+    for _ in 0..depth {
+        offset = match path.get(offset + 1..).and_then(|rest| rest.find('/')) {
+            Some(slash) => offset + 1 + slash,
+            None => path.len(),
+        };
+    }
+    &path[offset..]
+}
+
+/// Finds routes that can never be selected because another route without
+/// predicates matches every path they do and always outranks them. Returns
+/// pairs of `(shadowed, shadowed_by)` indexes into `routes`.
+pub fn shadowed_routes(routes: &[Route]) -> Vec<(usize, usize)> {
+    let patterns: Vec<Option<PathPattern>> = routes
+        .iter()
+        .map(|route| PathPattern::parse(&route.path).ok())
+        .collect();
+
+    let mut shadowed = Vec::new();
+    for (index, route) in routes.iter().enumerate() {
+        let Some(pattern) = &patterns[index] else {
+            continue;
+        };
+        let by = routes.iter().enumerate().find(|(other_index, other)| {
+            *other_index != index
+                && other.host == route.host
+                && other.predicate_count() == 0
+                && other.priority() > route.priority()
+                && patterns[*other_index]
+                    .as_ref()
+                    .is_some_and(|other_pattern| other_pattern.covers(pattern))
+        });
+        if let Some((by, _)) = by {
+            shadowed.push((index, by));
+        }
+    }
+    shadowed
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn table(routes: Vec<Route>) -> RouteTable {
+        RouteTable::new(GatewayConfig {
+            routes,
+            ..Default::default()
+        })
+    }
+
+    fn request(host: Option<&str>, uri: &str) -> Request<()> {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(host) = host {
+            builder = builder.header(HOST, host);
+        }
+        builder.body(()).unwrap()
+    }
+
     fn create_test_routes() -> Vec<Route> {
         vec![
             Route {
                 path: "/api".to_string(),
                 backend_url: "http://api.example.com".to_string(),
+                ..Default::default()
             },
             Route {
                 path: "/auth".to_string(),
                 backend_url: "http://auth.example.com".to_string(),
+                ..Default::default()
             },
             Route {
                 path: "/v1/users".to_string(),
                 backend_url: "http://users.example.com".to_string(),
+                ..Default::default()
             },
         ]
     }
@@ -38,7 +706,10 @@ mod tests {
     #[test]
     fn test_exact_path_match() {
         let routes = create_test_routes();
-        let result = match_route(&routes, "/api");
+        let routes = table(routes);
+        let result = routes
+            .match_route(&request(None, "/api"))
+            .map(|matched| matched.route);
 
         assert!(result.is_some());
         assert_eq!(result.unwrap().path, "/api");
@@ -48,7 +719,10 @@ mod tests {
     #[test]
     fn test_prefix_path_match() {
         let routes = create_test_routes();
-        let result = match_route(&routes, "/api/users/123");
+        let routes = table(routes);
+        let result = routes
+            .match_route(&request(None, "/api/users/123"))
+            .map(|matched| matched.route);
 
         assert!(result.is_some());
         assert_eq!(result.unwrap().path, "/api");
@@ -57,7 +731,10 @@ mod tests {
     #[test]
     fn test_nested_path_match() {
         let routes = create_test_routes();
-        let result = match_route(&routes, "/v1/users/profile");
+        let routes = table(routes);
+        let result = routes
+            .match_route(&request(None, "/v1/users/profile"))
+            .map(|matched| matched.route);
 
         assert!(result.is_some());
         assert_eq!(result.unwrap().path, "/v1/users");
@@ -67,34 +744,86 @@ mod tests {
     #[test]
     fn test_no_match() {
         let routes = create_test_routes();
-        let result = match_route(&routes, "/nonexistent");
+        let routes = table(routes);
+        let result = routes
+            .match_route(&request(None, "/nonexistent"))
+            .map(|matched| matched.route);
 
         assert!(result.is_none());
     }
 
     #[test]
-    fn test_first_match_wins() {
+    fn test_most_specific_wins() {
         let routes = vec![
             Route {
                 path: "/api".to_string(),
                 backend_url: "http://api1.example.com".to_string(),
+                ..Default::default()
             },
             Route {
                 path: "/api/users".to_string(),
                 backend_url: "http://api2.example.com".to_string(),
+                ..Default::default()
             },
         ];
 
-        // Should match the first route since it comes first
-        let result = match_route(&routes, "/api/users/123");
+        // Should match the longer prefix even though it is declared second
+        let routes = table(routes);
+        let result = routes
+            .match_route(&request(None, "/api/users/123"))
+            .map(|matched| matched.route);
         assert!(result.is_some());
+        assert_eq!(result.unwrap().backend_url, "http://api2.example.com");
+
+        let result = routes
+            .match_route(&request(None, "/api/orders"))
+            .map(|matched| matched.route);
         assert_eq!(result.unwrap().backend_url, "http://api1.example.com");
     }
 
+    #[test]
+    fn test_priority_overrides_specificity() {
+        let routes = vec![
+            Route {
+                path: "/api/users".to_string(),
+                backend_url: "http://users.example.com".to_string(),
+                ..Default::default()
+            },
+            Route {
+                path: "/api".to_string(),
+                backend_url: "http://api.example.com".to_string(),
+                priority: Some(10),
+                ..Default::default()
+            },
+        ];
+
+        let routes = table(routes);
+        let result = routes
+            .match_route(&request(None, "/api/users/123"))
+            .map(|matched| matched.route);
+        assert_eq!(result.unwrap().backend_url, "http://api.example.com");
+        assert_eq!(shadowed_routes(routes.routes()), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn test_no_shadowing_by_default() {
+        let mut routes = create_test_routes();
+        routes.push(Route {
+            path: "/".to_string(),
+            backend_url: "http://root.example.com".to_string(),
+            ..Default::default()
+        });
+
+        assert!(shadowed_routes(&routes).is_empty());
+    }
+
     #[test]
     fn test_empty_routes() {
         let routes = Vec::new();
-        let result = match_route(&routes, "/any/path");
+        let routes = table(routes);
+        let result = routes
+            .match_route(&request(None, "/any/path"))
+            .map(|matched| matched.route);
 
         assert!(result.is_none());
     }
@@ -104,9 +833,13 @@ mod tests {
         let routes = vec![Route {
             path: "/".to_string(),
             backend_url: "http://root.example.com".to_string(),
+            ..Default::default()
         }];
 
-        let result = match_route(&routes, "/anything");
+        let routes = table(routes);
+        let result = routes
+            .match_route(&request(None, "/anything"))
+            .map(|matched| matched.route);
         assert!(result.is_some());
         assert_eq!(result.unwrap().path, "/");
     }
@@ -114,7 +847,10 @@ mod tests {
     #[test]
     fn test_path_with_query_params() {
         let routes = create_test_routes();
-        let result = match_route(&routes, "/api/search?q=test");
+        let routes = table(routes);
+        let result = routes
+            .match_route(&request(None, "/api/search?q=test"))
+            .map(|matched| matched.route);
 
         assert!(result.is_some());
         assert_eq!(result.unwrap().path, "/api");
@@ -126,16 +862,363 @@ mod tests {
             Route {
                 path: "/app".to_string(),
                 backend_url: "http://app.example.com".to_string(),
+                ..Default::default()
             },
             Route {
                 path: "/application".to_string(),
                 backend_url: "http://application.example.com".to_string(),
+                ..Default::default()
             },
         ];
 
-        let result = match_route(&routes, "/application/config");
+        let routes = table(routes);
+        let result = routes
+            .match_route(&request(None, "/application/config"))
+            .map(|matched| matched.route);
         assert!(result.is_some());
         assert_eq!(result.unwrap().path, "/application");
     }
-}
 
+    #[test]
+    fn test_large_table() {
+        let routes = (0..500)
+            .map(|i| Route {
+                path: format!("/service{}/v{}", i, i % 3),
+                backend_url: format!("http://service{}.example.com", i),
+                ..Default::default()
+            })
+            .collect();
+        let routes = table(routes);
+
+        let result = routes
+            .match_route(&request(None, "/service250/v1/items/9"))
+            .map(|matched| matched.route);
+        assert_eq!(result.unwrap().backend_url, "http://service250.example.com");
+        assert!(
+            routes
+                .match_route(&request(None, "/service250/v2/items/9"))
+                .is_none()
+        );
+        assert!(
+            routes
+                .match_route(&request(None, "/service500/v2"))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_host_routing() {
+        let routes = vec![
+            Route {
+                path: "/".to_string(),
+                backend_url: "http://api.internal".to_string(),
+                host: Some("api.example.com".to_string()),
+                ..Default::default()
+            },
+            Route {
+                path: "/".to_string(),
+                backend_url: "http://tenants.internal".to_string(),
+                host: Some("*.example.com".to_string()),
+                ..Default::default()
+            },
+            Route {
+                path: "/".to_string(),
+                backend_url: "http://eu.internal".to_string(),
+                host: Some("*.eu.example.com".to_string()),
+                ..Default::default()
+            },
+            Route {
+                path: "/health".to_string(),
+                backend_url: "http://health.internal".to_string(),
+                ..Default::default()
+            },
+        ];
+        let routes = table(routes);
+        let backend = |host: Option<&str>, path: &str| {
+            routes
+                .match_route(&request(host, path))
+                .map(|matched| matched.route.backend_url.as_str())
+        };
+
+        assert_eq!(
+            backend(Some("API.example.com:8080"), "/x"),
+            Some("http://api.internal")
+        );
+        assert_eq!(
+            backend(Some("acme.example.com"), "/x"),
+            Some("http://tenants.internal")
+        );
+        assert_eq!(
+            backend(Some("acme.eu.example.com"), "/x"),
+            Some("http://eu.internal")
+        );
+        assert_eq!(backend(Some("example.com"), "/x"), None);
+        assert_eq!(
+            backend(Some("example.com"), "/health"),
+            Some("http://health.internal")
+        );
+        assert_eq!(backend(None, "/health"), Some("http://health.internal"));
+        // A host's own routes win over host-agnostic ones
+        assert_eq!(
+            backend(Some("api.example.com"), "/health"),
+            Some("http://api.internal")
+        );
+    }
+
+    #[test]
+    fn test_default_host_fallback() {
+        let mut config = GatewayConfig {
+            routes: vec![Route {
+                path: "/".to_string(),
+                backend_url: "http://www.internal".to_string(),
+                host: Some("www.example.com".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(
+            RouteTable::new(config.clone())
+                .match_route(&request(Some("10.0.0.1"), "/"))
+                .is_none()
+        );
+
+        config.server.default_host = Some("www.example.com".to_string());
+        let routes = RouteTable::new(config);
+        let result = routes
+            .match_route(&request(Some("10.0.0.1"), "/"))
+            .map(|matched| matched.route);
+        assert_eq!(result.unwrap().backend_url, "http://www.internal");
+        let result = routes
+            .match_route(&request(None, "/"))
+            .map(|matched| matched.route);
+        assert_eq!(result.unwrap().backend_url, "http://www.internal");
+    }
+
+    #[test]
+    fn test_predicates_fall_through() {
+        let routes = vec![
+            Route {
+                path: "/orders".to_string(),
+                backend_url: "http://orders.example.com".to_string(),
+                ..Default::default()
+            },
+            Route {
+                path: "/orders".to_string(),
+                backend_url: "http://orders-write.example.com".to_string(),
+                methods: vec!["POST".to_string()],
+                ..Default::default()
+            },
+            Route {
+                path: "/orders".to_string(),
+                backend_url: "http://orders-canary.example.com".to_string(),
+                headers: vec![ValueMatch {
+                    name: "X-Canary".to_string(),
+                    value: Some("true".to_string()),
+                    ..Default::default()
+                }],
+                query: vec![ValueMatch {
+                    name: "version".to_string(),
+                    regex: Some("^2".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        ];
+        let routes = table(routes);
+        let backend = |method: &str, uri: &str, canary: Option<&str>| {
+            let mut builder = Request::builder().method(method).uri(uri);
+            if let Some(canary) = canary {
+                builder = builder.header("x-canary", canary);
+            }
+            let req = builder.body(()).unwrap();
+            routes.match_route(&req).unwrap().route.backend_url.clone()
+        };
+
+        assert_eq!(backend("GET", "/orders", None), "http://orders.example.com");
+        assert_eq!(
+            backend("POST", "/orders/1", None),
+            "http://orders-write.example.com"
+        );
+        assert_eq!(
+            backend("GET", "/orders?version=2.1", Some("true")),
+            "http://orders-canary.example.com"
+        );
+        // Every predicate has to hold
+        assert_eq!(
+            backend("GET", "/orders?version=1", Some("true")),
+            "http://orders.example.com"
+        );
+        assert_eq!(
+            backend("GET", "/orders?version=2", Some("false")),
+            "http://orders.example.com"
+        );
+    }
+
+    #[test]
+    fn test_path_templates() {
+        let routes = vec![
+            Route {
+                path: "/users/{id}/orders/{order_id}".to_string(),
+                backend_url: "http://orders.example.com".to_string(),
+                rewrite: Some(Rewrite::Template {
+                    path: "/v2/customers/{id}/orders/{order_id}".to_string(),
+                }),
+                ..Default::default()
+            },
+            Route {
+                path: "/users/me".to_string(),
+                backend_url: "http://me.example.com".to_string(),
+                ..Default::default()
+            },
+            Route {
+                path: "/files/{*rest}".to_string(),
+                backend_url: "http://files.example.com".to_string(),
+                rewrite: Some(Rewrite::Template {
+                    path: "/storage/{rest}".to_string(),
+                }),
+                ..Default::default()
+            },
+        ];
+        let routes = table(routes);
+
+        let req = request(None, "/users/42/orders/7/items?page=2");
+        let matched = routes.match_route(&req).unwrap();
+        assert_eq!(matched.param("id"), Some("42"));
+        assert_eq!(matched.param("order_id"), Some("7"));
+        assert_eq!(matched.remainder, "/items");
+        assert_eq!(
+            matched.backend_uri(&matched.route.backend_url, req.uri().query()),
+            "http://orders.example.com/v2/customers/42/orders/7/items?page=2"
+        );
+
+        // Literal segments beat parameters at the same depth
+        let req = request(None, "/users/me/orders");
+        let matched = routes.match_route(&req).unwrap();
+        assert_eq!(matched.route.backend_url, "http://me.example.com");
+
+        let req = request(None, "/files/a/b.txt");
+        let matched = routes.match_route(&req).unwrap();
+        assert_eq!(matched.param("rest"), Some("a/b.txt"));
+        assert_eq!(matched.upstream_path(), "/storage/a/b.txt");
+
+        assert!(
+            routes
+                .match_route(&request(None, "/users//orders/1"))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_strip_prefix_keeps_remainder() {
+        let routes = table(vec![Route {
+            path: "/".to_string(),
+            backend_url: "http://root.example.com".to_string(),
+            ..Default::default()
+        }]);
+
+        let matched = routes
+            .match_route(&request(None, "/anything/else"))
+            .unwrap();
+        assert_eq!(matched.upstream_path(), "/anything/else");
+    }
+
+    #[test]
+    fn test_default_backend() {
+        let mut config = GatewayConfig {
+            routes: create_test_routes(),
+            ..Default::default()
+        };
+        assert!(
+            table(config.routes.clone())
+                .default_match("/nope")
+                .is_none()
+        );
+
+        config.default_backend = Some("http://fallback.example.com".to_string());
+        let routes = RouteTable::new(config);
+        assert!(routes.match_route(&request(None, "/nope/x")).is_none());
+        let matched = routes.default_match("/nope/x").unwrap();
+        assert_eq!(
+            matched.backend_uri(&matched.route.backend_url, Some("a=1")),
+            "http://fallback.example.com/nope/x?a=1"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_modes() {
+        let route = |path: &str, rewrite: Rewrite| Route {
+            path: path.to_string(),
+            backend_url: "http://backend.example.com".to_string(),
+            rewrite: Some(rewrite),
+            ..Default::default()
+        };
+        let routes = table(vec![
+            route("/keep", Rewrite::Preserve),
+            route(
+                "/old",
+                Rewrite::ReplacePrefix {
+                    prefix: "/new".to_string(),
+                },
+            ),
+            route(
+                "/api",
+                Rewrite::Regex {
+                    pattern: "^/api/v([0-9]+)/(.*)$".to_string(),
+                    replacement: "/$2/version/$1".to_string(),
+                },
+            ),
+        ]);
+        let upstream = |path: &str| {
+            routes
+                .match_route(&request(None, path))
+                .unwrap()
+                .upstream_path()
+        };
+
+        assert_eq!(upstream("/keep/a"), "/keep/a");
+        assert_eq!(upstream("/old/a/b"), "/new/a/b");
+        assert_eq!(upstream("/old"), "/new");
+        assert_eq!(upstream("/api/v2/users"), "/users/version/2");
+        assert_eq!(upstream("/api/users"), "/api/users");
+    }
+
+    #[test]
+    fn test_regex_rewrite_keeps_upstream_host() {
+        let routes = table(vec![Route {
+            path: "/api".to_string(),
+            backend_url: "http://backend.internal".to_string(),
+            rewrite: Some(Rewrite::Regex {
+                pattern: "^/api/(.*)$".to_string(),
+                replacement: "$1".to_string(),
+            }),
+            ..Default::default()
+        }]);
+        let backend_uri = |path: &str| {
+            let matched = routes.match_route(&request(None, path)).unwrap();
+            matched.backend_uri(&matched.route.backend_url, None)
+        };
+
+        assert_eq!(
+            backend_uri("/api/.attacker.example/x"),
+            "http://backend.internal/.attacker.example/x"
+        );
+        assert_eq!(
+            backend_uri("/api/@attacker.example/x"),
+            "http://backend.internal/@attacker.example/x"
+        );
+    }
+
+    #[test]
+    fn test_grpc_routes_keep_method_path() {
+        let routes = table(vec![Route {
+            path: "/helloworld.Greeter".to_string(),
+            backend_url: "http://greeter.example.com:50051".to_string(),
+            grpc: true,
+            ..Default::default()
+        }]);
+        let matched = routes
+            .match_route(&request(None, "/helloworld.Greeter/SayHello"))
+            .unwrap();
+        assert_eq!(matched.upstream_path(), "/helloworld.Greeter/SayHello");
+    }
+}
@@ -1,34 +1,258 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use crate::{config::extract_routes, metrics::MetricsCollector, storage::MetricsStore};
+use crate::{
+    balancer::UpstreamRegistry,
+    config::{GatewayConfig, RateLimitConfig, Timeouts, UpstreamConfig, load_config, save_config},
+    error::{AdminError, ConfigError},
+    metrics::MetricsCollector,
+    retry::RetryBudgets,
+    router::RouteTable,
+    storage::MetricsStore,
+};
+use arc_swap::ArcSwap;
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-#[derive(Clone)]
 pub struct AppState {
-    pub client: Client,
-    pub routes: Vec<Route>,
+    /// Pool settings from the config the gateway started with
+    upstream: UpstreamConfig,
+    /// Settings from the config the gateway started with that only take
+    /// effect on a restart, to warn when a reload changes them
+    bind: (String, Option<String>),
+    rate_limit: RateLimitConfig,
+    /// One client per connect timeout in use and per HTTP version, since
+    /// reqwest only sets those per client. Kept across reloads so connection
+    /// pools survive them.
+    clients: Mutex<HashMap<(Duration, bool), Client>>,
+    pub config_path: PathBuf,
+    pub routes: ArcSwap<RouteTable>,
+    /// Serializes config reloads and admin API edits. Holds whether the
+    /// active routes have admin API edits the config file doesn't.
+    config_lock: Mutex<bool>,
     pub metrics_store: Arc<MetricsStore>,
     pub metrics_collector: Arc<MetricsCollector>,
-}
-
-#[derive(Clone, Serialize, Deserialize)]
-pub struct Route {
-    pub path: String,
-    pub backend_url: String,
+    pub retry_budgets: RetryBudgets,
+    pub upstreams: UpstreamRegistry,
+    /// Name this process adds to `Via` headers, random per run so requests
+    /// that loop back to it can be told apart from ones that passed through
+    /// other instances
+    pub instance: String,
 }
 
 impl AppState {
-    pub fn new() -> Self {
-        let client = Client::builder().use_rustls_tls().build().unwrap();
-        let routes = extract_routes();
+    pub fn new(config_path: PathBuf, config: GatewayConfig) -> Self {
         let (metrics_store, _ws_receiver) = MetricsStore::new();
         let metrics_collector = Arc::new(MetricsCollector::new(Arc::new(metrics_store.clone())));
         Self {
-            client,
-            routes,
+            upstream: config.upstream.clone(),
+            bind: (config.server.bind.clone(), config.server.admin_bind.clone()),
+            rate_limit: config.rate_limit.clone(),
+            clients: Mutex::new(HashMap::new()),
+            config_path,
+            routes: ArcSwap::from_pointee(RouteTable::new(config)),
+            config_lock: Mutex::new(false),
             metrics_store: Arc::new(metrics_store),
             metrics_collector,
+            retry_budgets: RetryBudgets::default(),
+            upstreams: UpstreamRegistry::default(),
+            instance: format!("gateway-api-{:08x}", rand::random::<u32>()),
         }
     }
+
+    /// Client to reach a backend with `timeouts`, speaking only HTTP/2 if
+    /// `http2_only`. Only the connect timeout is set here; the proxy applies
+    /// the others itself once it knows what kind of response it got.
+    pub fn client(&self, timeouts: &Timeouts, http2_only: bool) -> Client {
+        let mut clients = self.clients.lock().unwrap();
+        clients
+            .entry((timeouts.connect, http2_only))
+            .or_insert_with(|| {
+                let mut client = Client::builder()
+                    .use_rustls_tls()
+                    .connect_timeout(timeouts.connect);
+                if http2_only {
+                    client = client.http2_prior_knowledge();
+                }
+                if let Some(max_idle) = self.upstream.pool_max_idle_per_host {
+                    client = client.pool_max_idle_per_host(max_idle);
+                }
+                if let Some(idle_timeout) = self.upstream.pool_idle_timeout_ms {
+                    client = client.pool_idle_timeout(Duration::from_millis(idle_timeout));
+                }
+                client.build().unwrap()
+            })
+            .clone()
+    }
+
+    /// Re-reads the config file and swaps in the new routing table. On error
+    /// the current table stays in place.
+    pub fn reload_config(&self) -> Result<usize, ConfigError> {
+        let mut unsaved = self.config_lock.lock().unwrap();
+        let config = load_config(&self.config_path)?;
+        let route_count = config.routes.len();
+        let ignored = self.restart_only_changes(&config);
+        if !ignored.is_empty() {
+            warn!(
+                "Reloading {} doesn't apply the changes to {}; restart the gateway for them to take effect",
+                self.config_path.display(),
+                ignored.join(", ")
+            );
+        }
+        self.routes.store(Arc::new(RouteTable::new(config)));
+        if std::mem::take(&mut *unsaved) {
+            warn!(
+                "Reloading {} discarded route changes made through the admin API; set server.persist_route_changes to keep them",
+                self.config_path.display()
+            );
+        }
+        Ok(route_count)
+    }
+
+    /// Settings `config` changes from the ones the gateway started with that
+    /// are only read at startup
+    fn restart_only_changes(&self, config: &GatewayConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if config.server.bind != self.bind.0 {
+            changed.push("server.bind");
+        }
+        if config.server.admin_bind != self.bind.1 {
+            changed.push("server.admin_bind");
+        }
+        if config.rate_limit != self.rate_limit {
+            changed.push("rate_limit");
+        }
+        if config.upstream != self.upstream {
+            changed.push("upstream");
+        }
+        changed
+    }
+
+    /// Applies `edit` to a copy of the active config, validates the result
+    /// and swaps it in, writing it back to the config file if
+    /// `server.persist_route_changes` is set.
+    pub fn update_config<T>(
+        &self,
+        edit: impl FnOnce(&mut GatewayConfig) -> Result<T, AdminError>,
+    ) -> Result<T, AdminError> {
+        let mut unsaved = self.config_lock.lock().unwrap();
+        let mut config = self.routes.load().config().clone();
+        let value = edit(&mut config)?;
+        config.validate().map_err(AdminError::Invalid)?;
+
+        if config.server.persist_route_changes {
+            save_config(&self.config_path, &config).map_err(AdminError::Persist)?;
+        } else {
+            *unsaved = true;
+        }
+        self.routes.store(Arc::new(RouteTable::new(config)));
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Route;
+    use std::fs;
+
+    fn route(path: &str) -> Route {
+        Route {
+            id: path.trim_start_matches('/').to_string(),
+            path: path.to_string(),
+            backend_url: "http://backend.internal".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn state_with_file() -> AppState {
+        let path =
+            std::env::temp_dir().join(format!("gateway-state-{:08x}.json", rand::random::<u32>()));
+        fs::write(
+            &path,
+            r#"{"routes": [{"id": "api", "path": "/api", "backend_url": "http://api.internal"}]}"#,
+        )
+        .unwrap();
+        AppState::new(path.clone(), load_config(&path).unwrap())
+    }
+
+    #[test]
+    fn test_update_config_stores_valid_edits() {
+        let state = state_with_file();
+        let count = state
+            .update_config(|config| {
+                config.routes.push(route("/echo"));
+                Ok(config.routes.len())
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(state.routes.load().routes()[1].id, "echo");
+        assert!(*state.config_lock.lock().unwrap());
+
+        // The edit wasn't saved, so the file's routes come back on reload
+        assert_eq!(state.reload_config().unwrap(), 1);
+        assert!(!*state.config_lock.lock().unwrap());
+        fs::remove_file(&state.config_path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_reports_restart_only_changes() {
+        let state = state_with_file();
+        let routes =
+            r#""routes": [{"id": "api", "path": "/api", "backend_url": "http://api.internal"}]"#;
+        fs::write(
+            &state.config_path,
+            format!(
+                r#"{{"server": {{"bind": "0.0.0.0:9000", "default_host": "example.com"}},
+                    "rate_limit": {{"burst_size": 1}}, {}}}"#,
+                routes
+            ),
+        )
+        .unwrap();
+        let config = load_config(&state.config_path).unwrap();
+        assert_eq!(
+            state.restart_only_changes(&config),
+            ["server.bind", "rate_limit"]
+        );
+
+        // The new values still aren't in effect after the reload
+        assert_eq!(state.reload_config().unwrap(), 1);
+        assert_eq!(
+            state.restart_only_changes(&config),
+            ["server.bind", "rate_limit"]
+        );
+        fs::write(
+            &state.config_path,
+            format!(
+                r#"{{"upstream": {{"pool_max_idle_per_host": 4}}, {}}}"#,
+                routes
+            ),
+        )
+        .unwrap();
+        let config = load_config(&state.config_path).unwrap();
+        assert_eq!(state.restart_only_changes(&config), ["upstream"]);
+        fs::remove_file(&state.config_path).unwrap();
+    }
+
+    #[test]
+    fn test_update_config_rejects_invalid_edits() {
+        let state = state_with_file();
+        fs::remove_file(&state.config_path).unwrap();
+        let result = state.update_config(|config| {
+            config.routes.push(route("no-slash"));
+            Ok(())
+        });
+        assert!(matches!(
+            result,
+            Err(AdminError::Invalid(ConfigError::Invalid(_)))
+        ));
+        let routes = state.routes.load();
+        assert_eq!(routes.routes().len(), 1);
+        assert_eq!(routes.routes()[0].id, "api");
+        assert!(!*state.config_lock.lock().unwrap());
+    }
 }
@@ -6,18 +6,36 @@ use std::{
     },
 };
 
+use chrono::Utc;
 use tokio::sync::broadcast;
 
-use crate::models::{RequestLog, SummaryMetrics, WsMessage};
+use crate::models::{
+    CircuitBreakerEvent, CircuitState, RequestLog, SummaryMetrics, TunnelLog, UnmatchedPath,
+    WsMessage,
+};
+
+/// Distinct unmatched paths tracked. Scanners try endless paths, so once
+/// full the least requested one makes way for a new one.
+const MAX_UNMATCHED_PATHS: usize = 1000;
+
+/// Unmatched paths listed in the metrics
+const TOP_UNMATCHED: usize = 10;
 
 #[derive(Clone)]
 pub struct MetricsStore {
     request_logs: Arc<RwLock<VecDeque<RequestLog>>>,
     total_requests: Arc<AtomicU64>,
     total_errors: Arc<AtomicU64>,
+    unmatched_requests: Arc<AtomicU64>,
+    unmatched_paths: Arc<RwLock<HashMap<String, UnmatchedPath>>>,
+    total_timeouts: Arc<AtomicU64>,
     active_connections: Arc<AtomicU64>,
+    tunnel_logs: Arc<RwLock<VecDeque<TunnelLog>>>,
+    total_tunnels: Arc<AtomicU64>,
+    active_tunnels: Arc<AtomicU64>,
 
     route_stats: Arc<RwLock<HashMap<String, u64>>>,
+    circuit_breakers: Arc<RwLock<HashMap<String, CircuitState>>>,
 
     broadcaster: broadcast::Sender<WsMessage>,
 }
@@ -29,8 +47,15 @@ impl MetricsStore {
             request_logs: Arc::new(RwLock::new(VecDeque::new())),
             total_requests: Arc::new(AtomicU64::new(0)),
             total_errors: Arc::new(AtomicU64::new(0)),
+            unmatched_requests: Arc::new(AtomicU64::new(0)),
+            unmatched_paths: Arc::new(RwLock::new(HashMap::new())),
+            total_timeouts: Arc::new(AtomicU64::new(0)),
             active_connections: Arc::new(AtomicU64::new(0)),
+            tunnel_logs: Arc::new(RwLock::new(VecDeque::new())),
+            total_tunnels: Arc::new(AtomicU64::new(0)),
+            active_tunnels: Arc::new(AtomicU64::new(0)),
             route_stats: Arc::new(RwLock::new(HashMap::new())),
+            circuit_breakers: Arc::new(RwLock::new(HashMap::new())),
             broadcaster: tx,
         };
         (store, rx)
@@ -61,6 +86,63 @@ impl MetricsStore {
         let _ = self.broadcaster.send(WsMessage::NewLog { log });
     }
 
+    /// Counts a request for `path` from `client_ip` that no route matched,
+    /// whether or not it then went to the default backend
+    pub fn add_unmatched(&self, path: &str, client_ip: &str) {
+        self.unmatched_requests.fetch_add(1, Ordering::Relaxed);
+
+        let mut paths = self.unmatched_paths.write().unwrap();
+        if !paths.contains_key(path)
+            && paths.len() >= MAX_UNMATCHED_PATHS
+            && let Some(least) = paths
+                .values()
+                .min_by_key(|unmatched| (unmatched.count, unmatched.last_seen))
+                .map(|unmatched| unmatched.path.clone())
+        {
+            paths.remove(&least);
+        }
+        let unmatched = paths
+            .entry(path.to_string())
+            .or_insert_with(|| UnmatchedPath {
+                path: path.to_string(),
+                count: 0,
+                last_client_ip: String::new(),
+                last_seen: Utc::now(),
+            });
+        unmatched.count += 1;
+        unmatched.last_client_ip = client_ip.to_string();
+        unmatched.last_seen = Utc::now();
+    }
+
+    pub fn add_timeout(&self) {
+        self.total_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn open_tunnel(&self) {
+        self.total_tunnels.fetch_add(1, Ordering::Relaxed);
+        self.active_tunnels.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn close_tunnel(&self, log: TunnelLog) {
+        self.active_tunnels.fetch_sub(1, Ordering::Relaxed);
+        let mut logs = self.tunnel_logs.write().unwrap();
+        if logs.len() >= 100 {
+            logs.pop_front();
+        }
+        logs.push_back(log);
+    }
+
+    /// Records an upstream's circuit breaker changing state and broadcasts
+    /// it
+    pub fn set_circuit_state(&self, upstream: &str, state: CircuitState) {
+        self.circuit_breakers
+            .write()
+            .unwrap()
+            .insert(upstream.to_string(), state);
+        let event = CircuitBreakerEvent::new(upstream.to_string(), state);
+        let _ = self.broadcaster.send(WsMessage::CircuitBreaker { event });
+    }
+
     pub fn get_metrics(&self) -> SummaryMetrics {
         let recent_logs = {
             let logs = self.request_logs.read().unwrap();
@@ -68,13 +150,32 @@ impl MetricsStore {
         };
 
         let route_stats = self.route_stats.read().unwrap().clone();
+        let recent_tunnels = {
+            let logs = self.tunnel_logs.read().unwrap();
+            logs.iter().rev().take(50).cloned().collect()
+        };
+        let circuit_breakers = self.circuit_breakers.read().unwrap().clone();
+        let top_unmatched = {
+            let paths = self.unmatched_paths.read().unwrap();
+            let mut top: Vec<UnmatchedPath> = paths.values().cloned().collect();
+            top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.path.cmp(&b.path)));
+            top.truncate(TOP_UNMATCHED);
+            top
+        };
 
         SummaryMetrics {
             total_requests: self.total_requests.load(Ordering::Relaxed),
             total_errors: self.total_errors.load(Ordering::Relaxed),
+            unmatched_requests: self.unmatched_requests.load(Ordering::Relaxed),
+            top_unmatched,
+            total_timeouts: self.total_timeouts.load(Ordering::Relaxed),
             active_connections: self.active_connections.load(Ordering::Relaxed),
             recent_logs,
             route_stats,
+            total_tunnels: self.total_tunnels.load(Ordering::Relaxed),
+            active_tunnels: self.active_tunnels.load(Ordering::Relaxed),
+            recent_tunnels,
+            circuit_breakers,
         }
     }
 
@@ -103,3 +204,32 @@ fn extract_route_path(path: &str) -> Option<&str> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_unmatched_paths() {
+        let (store, _rx) = MetricsStore::new();
+        for client in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            store.add_unmatched("/wp-login.php", client);
+        }
+        store.add_unmatched("/.env", "10.0.0.9");
+        for i in 0..MAX_UNMATCHED_PATHS {
+            store.add_unmatched(&format!("/scan/{}", i), "10.0.0.9");
+        }
+
+        let metrics = store.get_metrics();
+        assert_eq!(metrics.unmatched_requests, 4 + MAX_UNMATCHED_PATHS as u64);
+        assert_eq!(metrics.top_unmatched.len(), TOP_UNMATCHED);
+        let top = &metrics.top_unmatched[0];
+        assert_eq!((top.path.as_str(), top.count), ("/wp-login.php", 3));
+        assert_eq!(top.last_client_ip, "10.0.0.3");
+
+        // Making room evicted a path requested once, not the top one
+        let paths = store.unmatched_paths.read().unwrap();
+        assert_eq!(paths.len(), MAX_UNMATCHED_PATHS);
+        assert!(paths.contains_key("/wp-login.php"));
+    }
+}
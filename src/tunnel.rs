@@ -0,0 +1,284 @@
+use std::{future, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{
+        FromRequestParts,
+        ws::{self, WebSocket, WebSocketUpgrade},
+    },
+    response::Response,
+};
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
+use http::{
+    HeaderMap,
+    header::{
+        SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION,
+        UPGRADE,
+    },
+    request::Parts,
+};
+use tokio::{
+    net::TcpStream,
+    time::{Instant, sleep, sleep_until, timeout},
+};
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, connect_async,
+    tungstenite::{self, client::IntoClientRequest, protocol::frame::coding::CloseCode},
+};
+use tracing::info;
+
+use crate::{
+    balancer::InFlight,
+    config::Timeouts,
+    error::ProxyError,
+    hop_by_hop::strip_hop_by_hop,
+    models::{TunnelClose, TunnelLog},
+    storage::MetricsStore,
+};
+
+type Upstream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Whether the request asks to upgrade to a WebSocket
+pub fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// Opens a WebSocket connection to `backend_uri`, then upgrades the
+/// client's connection and forwards messages between the two until either
+/// side closes or a timeout passes. `log` is filled in and recorded when
+/// the tunnel closes.
+pub async fn open(
+    parts: &mut Parts,
+    backend_uri: &str,
+    headers: HeaderMap,
+    timeouts: Timeouts,
+    in_flight: InFlight,
+    mut log: TunnelLog,
+    metrics: Arc<MetricsStore>,
+) -> Result<Response, ProxyError> {
+    let upgrade = WebSocketUpgrade::from_request_parts(parts, &())
+        .await
+        .map_err(|rejection| ProxyError::UpgradeError(rejection.body_text()))?;
+
+    // http -> ws, https -> wss
+    let mut request = backend_uri
+        .replacen("http", "ws", 1)
+        .into_client_request()
+        .map_err(|e| ProxyError::BackendError(e.to_string()))?;
+    for (name, value) in &headers {
+        // The handshake with the upstream is our own, and extensions such
+        // as compression would have to be negotiated on both sides
+        if [
+            SEC_WEBSOCKET_KEY,
+            SEC_WEBSOCKET_VERSION,
+            SEC_WEBSOCKET_EXTENSIONS,
+        ]
+        .contains(name)
+        {
+            continue;
+        }
+        request.headers_mut().append(name, value.clone());
+    }
+
+    let (upstream, response) = match timeout(timeouts.connect, connect_async(request)).await {
+        Ok(Ok(connected)) => connected,
+        // The upstream refused the upgrade, so pass its answer on
+        Ok(Err(tungstenite::Error::Http(response))) => {
+            let (mut parts, body) = response.into_parts();
+            strip_hop_by_hop(&mut parts.headers);
+            return Ok(Response::from_parts(
+                parts,
+                Body::from(body.unwrap_or_default()),
+            ));
+        }
+        Ok(Err(e)) => return Err(ProxyError::BackendError(e.to_string())),
+        Err(_) => {
+            return Err(ProxyError::Timeout(format!(
+                "WebSocket connection to {} took longer than {:?}",
+                backend_uri, timeouts.connect
+            )));
+        }
+    };
+
+    let protocol = response
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok());
+    let upgrade = match protocol {
+        Some(protocol) => upgrade.protocols([protocol.to_string()]),
+        None => upgrade,
+    };
+
+    metrics.open_tunnel();
+    Ok(upgrade.on_upgrade(move |client| async move {
+        let _in_flight = in_flight;
+        let (closed_by, sent, received) = forward(client, upstream, &timeouts).await;
+        log.duration = Utc::now().signed_duration_since(log.timestamp);
+        log.bytes_sent = sent;
+        log.bytes_received = received;
+        log.closed_by = closed_by;
+        info!(
+            "WebSocket tunnel to {} closed by {:?} after {}s",
+            log.upstream,
+            closed_by,
+            log.duration.num_seconds()
+        );
+        metrics.close_tunnel(log);
+    }))
+}
+
+/// Passes messages both ways, returning why the tunnel closed and the
+/// bytes sent each way
+async fn forward(
+    client: WebSocket,
+    upstream: Upstream,
+    timeouts: &Timeouts,
+) -> (TunnelClose, u64, u64) {
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+    let deadline = timeouts
+        .websocket_lifetime
+        .map(|lifetime| Instant::now() + lifetime);
+    let (mut sent, mut received) = (0, 0);
+
+    // Close frames are passed on like any other message, and the tunnel
+    // ends once both sides have finished the closing handshake
+    let mut closing = None;
+    let closed_by = loop {
+        let lifetime = async {
+            match deadline {
+                Some(deadline) => sleep_until(deadline).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            message = client_rx.next() => {
+                let Some(Ok(message)) = message else {
+                    break closing.unwrap_or(TunnelClose::Client);
+                };
+                sent += client_len(&message);
+                if matches!(message, ws::Message::Close(_)) {
+                    closing.get_or_insert(TunnelClose::Client);
+                }
+                if upstream_tx.send(to_upstream(message)).await.is_err() {
+                    break closing.unwrap_or(TunnelClose::Upstream);
+                }
+            }
+            message = upstream_rx.next() => {
+                let Some(Ok(message)) = message else {
+                    break closing.unwrap_or(TunnelClose::Upstream);
+                };
+                received += message.len() as u64;
+                if message.is_close() {
+                    closing.get_or_insert(TunnelClose::Upstream);
+                }
+                let Some(message) = to_client(message) else {
+                    continue;
+                };
+                if client_tx.send(message).await.is_err() {
+                    break closing.unwrap_or(TunnelClose::Client);
+                }
+            }
+            _ = sleep(timeouts.websocket_idle) => break TunnelClose::IdleTimeout,
+            _ = lifetime => break TunnelClose::MaxLifetime,
+        }
+    };
+
+    if matches!(
+        closed_by,
+        TunnelClose::IdleTimeout | TunnelClose::MaxLifetime
+    ) {
+        let reason = format!("{:?}", closed_by);
+        let _ = client_tx
+            .send(ws::Message::Close(Some(ws::CloseFrame {
+                code: ws::close_code::AWAY,
+                reason: reason.as_str().into(),
+            })))
+            .await;
+        let _ = upstream_tx
+            .send(tungstenite::Message::Close(Some(
+                tungstenite::protocol::CloseFrame {
+                    code: CloseCode::Away,
+                    reason: reason.as_str().into(),
+                },
+            )))
+            .await;
+    }
+    (closed_by, sent, received)
+}
+
+fn client_len(message: &ws::Message) -> u64 {
+    let len = match message {
+        ws::Message::Text(text) => text.len(),
+        ws::Message::Binary(data) | ws::Message::Ping(data) | ws::Message::Pong(data) => data.len(),
+        ws::Message::Close(frame) => frame.as_ref().map_or(0, |frame| frame.reason.len()),
+    };
+    len as u64
+}
+
+fn to_upstream(message: ws::Message) -> tungstenite::Message {
+    match message {
+        ws::Message::Text(text) => tungstenite::Message::Text(text.as_str().into()),
+        ws::Message::Binary(data) => tungstenite::Message::Binary(data),
+        ws::Message::Ping(data) => tungstenite::Message::Ping(data),
+        ws::Message::Pong(data) => tungstenite::Message::Pong(data),
+        ws::Message::Close(frame) => {
+            tungstenite::Message::Close(frame.map(|frame| tungstenite::protocol::CloseFrame {
+                code: frame.code.into(),
+                reason: frame.reason.as_str().into(),
+            }))
+        }
+    }
+}
+
+/// Raw frames only come up when reading with a custom codec, so they are
+/// dropped
+fn to_client(message: tungstenite::Message) -> Option<ws::Message> {
+    Some(match message {
+        tungstenite::Message::Text(text) => ws::Message::Text(text.as_str().into()),
+        tungstenite::Message::Binary(data) => ws::Message::Binary(data),
+        tungstenite::Message::Ping(data) => ws::Message::Ping(data),
+        tungstenite::Message::Pong(data) => ws::Message::Pong(data),
+        tungstenite::Message::Close(frame) => {
+            ws::Message::Close(frame.map(|frame| ws::CloseFrame {
+                code: frame.code.into(),
+                reason: frame.reason.as_str().into(),
+            }))
+        }
+        tungstenite::Message::Frame(_) => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn test_websocket_upgrade_detection() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_websocket_upgrade(&headers));
+        headers.insert(UPGRADE, HeaderValue::from_static("h2c"));
+        assert!(!is_websocket_upgrade(&headers));
+        headers.insert(UPGRADE, HeaderValue::from_static("WebSocket"));
+        assert!(is_websocket_upgrade(&headers));
+    }
+
+    #[test]
+    fn test_messages_convert_both_ways() {
+        let close = ws::Message::Close(Some(ws::CloseFrame {
+            code: ws::close_code::NORMAL,
+            reason: "bye".into(),
+        }));
+        let upstream = to_upstream(close.clone());
+        assert_eq!(to_client(upstream), Some(close));
+
+        let text = ws::Message::Text("hello".into());
+        assert_eq!(client_len(&text), 5);
+        assert_eq!(to_client(to_upstream(text.clone())), Some(text));
+    }
+}